@@ -0,0 +1,83 @@
+use std::collections::BTreeSet;
+
+use crate::error::ExpressurError;
+use crate::tokenizer::{tokenize, Token};
+
+/// The names an expression refers to, split into the variables it reads and the functions it
+/// calls. Numeric literals are neither.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct References {
+    pub variables: BTreeSet<String>,
+    pub functions: BTreeSet<String>,
+}
+
+/// Lists the variables and functions an expression refers to without evaluating it, so it can
+/// be checked against the variables and functions that are available, or the inputs it needs
+/// can be fetched up front.
+///
+/// Only the tokens are examined, so an expression that tokenizes but does not parse still has
+/// its references listed.
+///
+/// # Errors
+/// A character that cannot start a token is reported as an ExpressurError::SyntaxError.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+///
+/// let references = referenced_variables("max(price, floor) * 1.2 + -tax").unwrap();
+/// assert_eq!(references.variables, ["floor", "price", "tax"].map(String::from).into());
+/// assert_eq!(references.functions, ["max"].map(String::from).into());
+/// ```
+pub fn referenced_variables(expression: &str) -> Result<References, ExpressurError> {
+    let mut references = References::default();
+    let tokens = tokenize(expression)?;
+    for (index, token) in tokens.iter().enumerate() {
+        if let Token::Identifier(_) = token {
+            let name = token.text(expression).to_string();
+            match tokens.get(index + 1) {
+                Some(Token::LeftParenthesis(_)) => references.functions.insert(name),
+                _ => references.variables.insert(name),
+            };
+        }
+    }
+    Ok(references)
+}
+
+#[test]
+fn test_referenced_variables() {
+    let strings = |names: &[&str]| -> BTreeSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    };
+    let tests = vec![
+        ("1 + 2.5 * -3", vec![], vec![]),
+        ("a + b * a", vec!["a", "b"], vec![]),
+        (
+            "(aplusb/ cplusaplusb)",
+            vec!["aplusb", "cplusaplusb"],
+            vec![],
+        ),
+        ("cash.cycle * -rate", vec!["cash.cycle", "rate"], vec![]),
+        (
+            "max(a, min(b, 2)) + round(c)",
+            vec!["a", "b", "c"],
+            vec!["max", "min", "round"],
+        ),
+        ("if(x > 1 and not y, z, 0)", vec!["x", "y", "z"], vec!["if"]),
+        ("x ? y : -1", vec!["x", "y"], vec![]),
+        ("1 +", vec![], vec![]),
+    ];
+    for (expression, variables, functions) in tests {
+        let references = referenced_variables(expression).unwrap();
+        assert_eq!(references.variables, strings(&variables), "{}", expression);
+        assert_eq!(references.functions, strings(&functions), "{}", expression);
+    }
+    assert_eq!(
+        referenced_variables("a # b"),
+        Err(ExpressurError::SyntaxError {
+            token: "#".to_string(),
+            position: 2
+        })
+    );
+}
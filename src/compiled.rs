@@ -0,0 +1,477 @@
+use crate::error::ExpressurError;
+use crate::evaluator::{
+    evaluate_operator, evaluate_unary_operator, reverse_polish_notate, truth, Operator, RpnToken,
+};
+use crate::explain::{Operation, Trace};
+use crate::functions::Function;
+use crate::prelude::*;
+
+/// A single step of a compiled expression, executed against a stack of Decimals.
+#[derive(Debug, Clone, PartialEq)]
+enum Instruction {
+    /// Pushes a numeric literal.
+    Push(Decimal),
+    /// Pushes the value of the variable in the given slot.
+    Load { slot: usize, position: usize },
+    /// Pops the operator's operands (two, or one for a unary operator) and pushes the result of
+    /// applying the operator to them.
+    Apply { operator: Operator, position: usize },
+    /// Skips the given number of instructions, leaving 0 on the stack, when the top of the stack
+    /// is false. Otherwise pops it.
+    JumpIfFalseOrPop(usize),
+    /// Skips the given number of instructions, leaving 1 on the stack, when the top of the stack
+    /// is true. Otherwise pops it.
+    JumpIfTrueOrPop(usize),
+    /// Replaces the top of the stack with 1 if it is true or 0 if it is false.
+    Truth,
+    /// Pops the top of the stack and skips the given number of instructions if it is false.
+    JumpIfFalse(usize),
+    /// Skips the given number of instructions.
+    Jump(usize),
+    /// Pops the function's arguments and pushes the result of calling it with them.
+    Call {
+        function: Function,
+        arguments: usize,
+        position: usize,
+    },
+}
+
+/// An expression that has been parsed once and can then be evaluated any number of times.
+///
+/// Numbers are parsed to Decimals, operators are resolved and variables are collected into
+/// slots when the expression is compiled, so evaluating only has to look up the variables and
+/// do the arithmetic.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+/// let compiled = CompiledExpression::compile("(a + b) * 2").unwrap();
+///
+/// let mut context = BTreeMap::new();
+/// context.insert("a".to_string(), dec!(1.));
+/// context.insert("b".to_string(), dec!(2.));
+/// assert_eq!(compiled.eval(&context).unwrap(), dec!(6.));
+///
+/// context.insert("b".to_string(), dec!(4.));
+/// assert_eq!(compiled.eval(&context).unwrap(), dec!(10.));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledExpression {
+    instructions: Vec<Instruction>,
+    variables: Vec<String>,
+    stack_size: usize,
+}
+
+/// Turns the instructions for the last three operands, a condition and two branches, into a
+/// conditional that only runs the branch the condition picks.
+fn conditional(instructions: &mut Vec<Instruction>, starts: &mut Vec<usize>) {
+    let otherwise = starts.pop().unwrap_or_default();
+    let then = starts.pop().unwrap_or_default();
+    instructions.insert(otherwise, Instruction::Jump(instructions.len() - otherwise));
+    instructions.insert(then, Instruction::JumpIfFalse(otherwise - then + 1));
+}
+
+impl CompiledExpression {
+    /// Parses an expression into a form that can be evaluated repeatedly.
+    ///
+    /// # Errors
+    /// If the expression is not a valid arithmetic expression, an ExpressurError is returned
+    /// identifying the offending token and its character offset.
+    pub fn compile(expression: &str) -> Result<CompiledExpression, ExpressurError> {
+        CompiledExpression::compile_with(expression, &Evaluator::default())
+    }
+
+    /// Parses an expression with the evaluator's options, so it may also call the evaluator's
+    /// custom functions.
+    pub(crate) fn compile_with(
+        expression: &str,
+        evaluator: &Evaluator,
+    ) -> Result<CompiledExpression, ExpressurError> {
+        let functions = &evaluator.functions;
+        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut variables: Vec<String> = Vec::new();
+        // where the instructions for each operand that would be on the stack at this point start
+        let mut starts: Vec<usize> = Vec::new();
+        let mut stack_size: usize = 0;
+
+        for (token, position) in reverse_polish_notate(expression, evaluator.power_precedence)? {
+            match token {
+                RpnToken::Operator(operator) => {
+                    let operands = match operator {
+                        _ if operator.is_unary() => 1,
+                        Operator::Conditional => 3,
+                        _ => 2,
+                    };
+                    // an operator without enough operands, such as "1 +"
+                    if starts.len() < operands {
+                        return Err(ExpressurError::SyntaxError {
+                            token: operator.symbol().to_string(),
+                            position,
+                        });
+                    }
+                    match operator {
+                        // the right operand is skipped when the left one decides the result,
+                        // otherwise it decides the result
+                        Operator::And | Operator::Or => {
+                            let right = starts.pop().unwrap_or_default();
+                            let skip = instructions.len() - right + 1;
+                            let jump = match operator {
+                                Operator::And => Instruction::JumpIfFalseOrPop(skip),
+                                _ => Instruction::JumpIfTrueOrPop(skip),
+                            };
+                            instructions.insert(right, jump);
+                            instructions.push(Instruction::Truth);
+                        }
+                        Operator::Conditional => conditional(&mut instructions, &mut starts),
+                        _ => {
+                            starts.truncate(starts.len() + 1 - operands);
+                            instructions.push(Instruction::Apply { operator, position });
+                        }
+                    }
+                }
+                RpnToken::Call(name, arguments) => {
+                    // custom functions take precedence over the built-ins
+                    let function = match functions.get(&name) {
+                        Some(custom) => Function::Custom(custom.clone()),
+                        // if only evaluates the branch it takes, so it is not a function call
+                        None if name == "if" => {
+                            if arguments != 3 || arguments > starts.len() {
+                                return Err(ExpressurError::WrongArgumentCount {
+                                    token: name,
+                                    position,
+                                    arguments,
+                                });
+                            }
+                            conditional(&mut instructions, &mut starts);
+                            continue;
+                        }
+                        None => match Function::from_name(&name) {
+                            Some(function) => function,
+                            None => {
+                                return Err(ExpressurError::UnknownFunction {
+                                    token: name,
+                                    position,
+                                })
+                            }
+                        },
+                    };
+                    let (min, max) = function.arity();
+                    if arguments < min || arguments > max || arguments > starts.len() {
+                        return Err(ExpressurError::WrongArgumentCount {
+                            token: name,
+                            position,
+                            arguments,
+                        });
+                    }
+                    // the call's result starts where its first argument did, or here if it has none
+                    let start = match arguments {
+                        0 => instructions.len(),
+                        _ => starts[starts.len() - arguments],
+                    };
+                    starts.truncate(starts.len() - arguments);
+                    starts.push(start);
+                    stack_size = stack_size.max(starts.len());
+                    instructions.push(Instruction::Call {
+                        function,
+                        arguments,
+                        position,
+                    });
+                }
+                RpnToken::Operand(operand) => {
+                    starts.push(instructions.len());
+                    stack_size = stack_size.max(starts.len());
+                    match Decimal::from_str_exact(&operand) {
+                        Ok(value) => instructions.push(Instruction::Push(value)),
+                        _ => {
+                            let slot = match variables.iter().position(|v| *v == operand) {
+                                Some(slot) => slot,
+                                None => {
+                                    variables.push(operand);
+                                    variables.len() - 1
+                                }
+                            };
+                            instructions.push(Instruction::Load { slot, position });
+                        }
+                    }
+                }
+            }
+        }
+
+        match starts.len() {
+            1 => Ok(CompiledExpression {
+                instructions,
+                variables,
+                stack_size,
+            }),
+            // nothing to evaluate
+            _ => Err(ExpressurError::SyntaxError {
+                token: String::new(),
+                position: expression.chars().count(),
+            }),
+        }
+    }
+
+    /// The names of the variables the expression references, in the order they first appear.
+    pub(crate) fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    /// Evaluates the compiled expression, looking up its variables in the context.
+    ///
+    /// # Errors
+    /// If a variable is not in the context or the arithmetic fails (such as a division by zero),
+    /// an ExpressurError is returned identifying the offending token and its character offset.
+    pub fn eval(&self, context: &impl Context) -> Result<Decimal, ExpressurError> {
+        self.run(context, None)
+    }
+
+    /// Evaluates the compiled expression, recording the variables it loads and the operations it
+    /// applies in the trace, if there is one.
+    pub(crate) fn run(
+        &self,
+        context: &impl Context,
+        mut trace: Option<&mut Trace>,
+    ) -> Result<Decimal, ExpressurError> {
+        let mut stack: Vec<Decimal> = Vec::with_capacity(self.stack_size);
+        let mut next = 0;
+        while let Some(instruction) = self.instructions.get(next) {
+            next += 1;
+            match instruction {
+                Instruction::Push(value) => {
+                    stack.push(*value);
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.push(None);
+                    }
+                }
+                Instruction::Load { slot, position } => {
+                    let name = &self.variables[*slot];
+                    match context.resolve(name) {
+                        Ok(Some(value)) => {
+                            stack.push(value);
+                            if let Some(trace) = trace.as_deref_mut() {
+                                trace.load(name, value);
+                            }
+                        }
+                        Ok(None) => {
+                            return Err(ExpressurError::UnknownVariable {
+                                token: name.to_string(),
+                                position: *position,
+                            })
+                        }
+                        Err(message) => {
+                            return Err(ExpressurError::ResolverFailed {
+                                token: name.to_string(),
+                                position: *position,
+                                message,
+                            })
+                        }
+                    }
+                }
+                Instruction::Apply { operator, position } if operator.is_unary() => {
+                    let x = stack.pop().unwrap_or_default();
+                    let result = evaluate_unary_operator(x, *operator, *position)?;
+                    stack.push(result);
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.apply(Operation::operator(*operator), &[x], result);
+                    }
+                }
+                Instruction::Apply { operator, position } => {
+                    // compile guarantees both operands are on the stack
+                    let y = stack.pop().unwrap_or_default();
+                    let x = stack.pop().unwrap_or_default();
+                    let result = evaluate_operator(x, y, *operator, *position)?;
+                    stack.push(result);
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.apply(Operation::operator(*operator), &[x, y], result);
+                    }
+                }
+                Instruction::JumpIfFalseOrPop(skip) | Instruction::JumpIfTrueOrPop(skip) => {
+                    let x = stack.pop().unwrap_or_default();
+                    let condition = !x.is_zero();
+                    let operator = match instruction {
+                        Instruction::JumpIfTrueOrPop(_) => Operator::Or,
+                        _ => Operator::And,
+                    };
+                    if condition == (operator == Operator::Or) {
+                        stack.push(truth(condition));
+                        next += skip;
+                        if let Some(trace) = trace.as_deref_mut() {
+                            trace.apply(Operation::operator(operator), &[x], truth(condition));
+                        }
+                    } else if let Some(trace) = trace.as_deref_mut() {
+                        trace.defer(operator, x);
+                    }
+                }
+                Instruction::JumpIfFalse(skip) => {
+                    if stack.pop().unwrap_or_default().is_zero() {
+                        next += skip;
+                    }
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.discard();
+                    }
+                }
+                Instruction::Jump(skip) => next += skip,
+                Instruction::Truth => {
+                    let x = stack.pop().unwrap_or_default();
+                    stack.push(truth(!x.is_zero()));
+                    if let Some(trace) = trace.as_deref_mut() {
+                        trace.resume(x, truth(!x.is_zero()));
+                    }
+                }
+                Instruction::Call {
+                    function,
+                    arguments,
+                    position,
+                } => {
+                    let start = stack.len().saturating_sub(*arguments);
+                    let result = function.apply(&stack[start..], *position)?;
+                    if let Some(trace) = trace.as_deref_mut() {
+                        let operation = Operation::Function(function.name().to_string());
+                        trace.apply(operation, &stack[start..], result);
+                    }
+                    stack.truncate(start);
+                    stack.push(result);
+                }
+            }
+        }
+        Ok(stack.pop().unwrap_or_default())
+    }
+}
+
+#[test]
+fn test_compile_once_eval_many() {
+    let compiled = CompiledExpression::compile("a * (b + a) - 2").unwrap();
+    assert_eq!(compiled.variables, vec!["a", "b"]);
+    assert_eq!(compiled.stack_size, 3);
+
+    for (a, b, expected) in [(dec!(1), dec!(2), dec!(1)), (dec!(2), dec!(3), dec!(8))] {
+        let context: BTreeMap<String, Decimal> = [("a".to_string(), a), ("b".to_string(), b)]
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(compiled.eval(&context).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_compile_typed_instructions() {
+    let compiled = CompiledExpression::compile("1.5 + x").unwrap();
+    assert_eq!(
+        compiled.instructions,
+        vec![
+            Instruction::Push(dec!(1.5)),
+            Instruction::Load {
+                slot: 0,
+                position: 6
+            },
+            Instruction::Apply {
+                operator: Operator::Add,
+                position: 4
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_compile_errors() {
+    assert_eq!(
+        CompiledExpression::compile("1 +"),
+        Err(ExpressurError::SyntaxError {
+            token: "+".to_string(),
+            position: 2
+        })
+    );
+    assert_eq!(
+        CompiledExpression::compile("(1 + 2) 3"),
+        Err(ExpressurError::SyntaxError {
+            token: "3".to_string(),
+            position: 8
+        })
+    );
+    assert_eq!(
+        CompiledExpression::compile("2 * avg(1, 2)"),
+        Err(ExpressurError::UnknownFunction {
+            token: "avg".to_string(),
+            position: 4
+        })
+    );
+    assert_eq!(
+        CompiledExpression::compile("abs(1, 2)"),
+        Err(ExpressurError::WrongArgumentCount {
+            token: "abs".to_string(),
+            position: 0,
+            arguments: 2
+        })
+    );
+    assert_eq!(
+        CompiledExpression::compile("max()"),
+        Err(ExpressurError::WrongArgumentCount {
+            token: "max".to_string(),
+            position: 0,
+            arguments: 0
+        })
+    );
+    let compiled = CompiledExpression::compile("x / 0").unwrap();
+    assert_eq!(
+        compiled.eval(&BTreeMap::new()),
+        Err(ExpressurError::UnknownVariable {
+            token: "x".to_string(),
+            position: 0
+        })
+    );
+}
+
+#[test]
+fn test_compile_short_circuit_jumps() {
+    let compiled = CompiledExpression::compile("a and b or c").unwrap();
+    assert_eq!(
+        compiled.instructions,
+        vec![
+            Instruction::Load {
+                slot: 0,
+                position: 0
+            },
+            Instruction::JumpIfFalseOrPop(2),
+            Instruction::Load {
+                slot: 1,
+                position: 6
+            },
+            Instruction::Truth,
+            Instruction::JumpIfTrueOrPop(2),
+            Instruction::Load {
+                slot: 2,
+                position: 11
+            },
+            Instruction::Truth,
+        ]
+    );
+}
+
+#[test]
+fn test_compile_conditional_jumps() {
+    let compiled = CompiledExpression::compile("a ? 1 : b + 2").unwrap();
+    assert_eq!(
+        compiled.instructions,
+        vec![
+            Instruction::Load {
+                slot: 0,
+                position: 0
+            },
+            Instruction::JumpIfFalse(2),
+            Instruction::Push(dec!(1)),
+            Instruction::Jump(3),
+            Instruction::Load {
+                slot: 1,
+                position: 8
+            },
+            Instruction::Push(dec!(2)),
+            Instruction::Apply {
+                operator: Operator::Add,
+                position: 10
+            },
+        ]
+    );
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use crate::prelude::*;
+
+/// Where expressions look up the values of their variables.
+///
+/// Maps from names to values are contexts, and so is any closure from a name to an optional
+/// value, so values can be read straight from wherever they are kept without copying them into a
+/// map first.
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+///
+/// let prices = |name: &str| match name {
+///     "price" => Some(dec!(2.5)),
+///     _ => None,
+/// };
+/// assert_eq!(evaluate_expression("price * 4", &prices).unwrap(), dec!(10));
+/// ```
+pub trait Context {
+    /// The value of the variable, or None if the context does not have it.
+    fn get(&self, name: &str) -> Option<Decimal>;
+
+    /// The value of the variable as seen by an evaluation, which can also fail with a message.
+    /// A failure is reported as an ExpressurError::ResolverFailed for the variable. By default
+    /// this is `get`, which never fails.
+    fn resolve(&self, name: &str) -> Result<Option<Decimal>, String> {
+        Ok(self.get(name))
+    }
+}
+
+impl Context for BTreeMap<String, Decimal> {
+    fn get(&self, name: &str) -> Option<Decimal> {
+        BTreeMap::get(self, name).copied()
+    }
+}
+
+impl<S: BuildHasher> Context for HashMap<String, Decimal, S> {
+    fn get(&self, name: &str) -> Option<Decimal> {
+        HashMap::get(self, name).copied()
+    }
+}
+
+impl<F: Fn(&str) -> Option<Decimal>> Context for F {
+    fn get(&self, name: &str) -> Option<Decimal> {
+        self(name)
+    }
+}
+
+/// Two contexts used as one: a variable is looked up in the top context first, and in the bottom
+/// context only when the top one does not have it. Neither context is copied, and layered
+/// contexts can themselves be layered.
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+///
+/// let defaults = BTreeMap::from([("rate".to_string(), dec!(0.1)), ("amount".to_string(), dec!(50))]);
+/// let overrides = BTreeMap::from([("rate".to_string(), dec!(0.2))]);
+/// let context = Layered::new(&overrides, &defaults);
+/// assert_eq!(evaluate_expression("amount * rate", &context).unwrap(), dec!(10));
+/// ```
+#[derive(Clone, Copy)]
+pub struct Layered<'a> {
+    top: &'a dyn Context,
+    bottom: &'a dyn Context,
+}
+
+impl<'a> Layered<'a> {
+    pub fn new(top: &'a dyn Context, bottom: &'a dyn Context) -> Layered<'a> {
+        Layered { top, bottom }
+    }
+}
+
+impl Context for Layered<'_> {
+    fn get(&self, name: &str) -> Option<Decimal> {
+        self.top.get(name).or_else(|| self.bottom.get(name))
+    }
+
+    fn resolve(&self, name: &str) -> Result<Option<Decimal>, String> {
+        match self.top.resolve(name)? {
+            Some(value) => Ok(Some(value)),
+            None => self.bottom.resolve(name),
+        }
+    }
+}
+
+/// A context with a resolver that is called for the variables the context does not have, so
+/// values can be fetched on demand, only for the variables an evaluation actually uses.
+///
+/// The resolver returns the value, None if it does not know the variable either, or an error
+/// message, which stops the evaluation with an ExpressurError::ResolverFailed naming the
+/// variable. It is not cached, so it can be called more than once for the same variable.
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+///
+/// let context = BTreeMap::from([("quantity".to_string(), dec!(3))]);
+/// let resolver = |name: &str| match name {
+///     "price" => Ok(Some(dec!(2))),
+///     "offline" => Err("feature store unavailable".to_string()),
+///     _ => Ok(None),
+/// };
+/// let context = WithResolver::new(&context, resolver);
+/// assert_eq!(evaluate_expression("price * quantity", &context).unwrap(), dec!(6));
+/// assert_eq!(
+///     evaluate_expression("offline + 1", &context),
+///     Err(ExpressurError::ResolverFailed {
+///         token: "offline".to_string(),
+///         position: 0,
+///         message: "feature store unavailable".to_string(),
+///     })
+/// );
+/// ```
+pub struct WithResolver<'a, R> {
+    context: &'a dyn Context,
+    resolver: R,
+}
+
+impl<'a, R> WithResolver<'a, R>
+where
+    R: Fn(&str) -> Result<Option<Decimal>, String>,
+{
+    pub fn new(context: &'a dyn Context, resolver: R) -> WithResolver<'a, R> {
+        WithResolver { context, resolver }
+    }
+}
+
+impl<R> Context for WithResolver<'_, R>
+where
+    R: Fn(&str) -> Result<Option<Decimal>, String>,
+{
+    fn get(&self, name: &str) -> Option<Decimal> {
+        self.context
+            .get(name)
+            .or_else(|| (self.resolver)(name).ok().flatten())
+    }
+
+    fn resolve(&self, name: &str) -> Result<Option<Decimal>, String> {
+        match self.context.resolve(name)? {
+            Some(value) => Ok(Some(value)),
+            None => (self.resolver)(name),
+        }
+    }
+}
+
+/// A named layer of values with an optional parent scope. A variable is looked up in the scope's
+/// own values first, then in its parent's, and so on, without copying any of them, and the scope
+/// can report which layer supplied a value.
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+///
+/// let assumptions = BTreeMap::from([("rate".to_string(), dec!(0.1)), ("amount".to_string(), dec!(50))]);
+/// let customer = BTreeMap::from([("rate".to_string(), dec!(0.2))]);
+/// let base = Scope::new("assumptions", &assumptions);
+/// let scope = base.child("customer 42", &customer);
+///
+/// assert_eq!(evaluate_expression("amount * rate", &scope).unwrap(), dec!(10));
+/// assert_eq!(scope.source("rate"), Some("customer 42"));
+/// assert_eq!(scope.source("amount"), Some("assumptions"));
+/// ```
+#[derive(Clone, Copy)]
+pub struct Scope<'a> {
+    name: &'a str,
+    values: &'a dyn Context,
+    parent: Option<&'a Scope<'a>>,
+}
+
+impl<'a> Scope<'a> {
+    /// Creates a scope without a parent.
+    pub fn new(name: &'a str, values: &'a dyn Context) -> Scope<'a> {
+        Scope {
+            name,
+            values,
+            parent: None,
+        }
+    }
+
+    /// Creates a scope whose values override this scope's.
+    pub fn child(&'a self, name: &'a str, values: &'a dyn Context) -> Scope<'a> {
+        Scope {
+            name,
+            values,
+            parent: Some(self),
+        }
+    }
+
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn parent(&self) -> Option<&'a Scope<'a>> {
+        self.parent
+    }
+
+    /// The value of the variable and the name of the scope that supplied it, which is the nearest
+    /// scope that has the variable.
+    pub fn lookup(&self, name: &str) -> Option<(Decimal, &'a str)> {
+        self.layers()
+            .find_map(|scope| scope.values.get(name).map(|value| (value, scope.name)))
+    }
+
+    /// The name of the scope that supplies the variable's value, if any does.
+    pub fn source(&self, name: &str) -> Option<&'a str> {
+        self.lookup(name).map(|(_, source)| source)
+    }
+
+    /// This scope followed by its ancestors, nearest first.
+    fn layers(&self) -> impl Iterator<Item = &Scope<'a>> {
+        std::iter::successors(Some(self), |scope| scope.parent)
+    }
+}
+
+impl Context for Scope<'_> {
+    fn get(&self, name: &str) -> Option<Decimal> {
+        self.lookup(name).map(|(value, _)| value)
+    }
+
+    fn resolve(&self, name: &str) -> Result<Option<Decimal>, String> {
+        for scope in self.layers() {
+            if let Some(value) = scope.values.resolve(name)? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_contexts() {
+    let btree: BTreeMap<String, Decimal> = [("a".to_string(), dec!(1))].iter().cloned().collect();
+    let hash: HashMap<String, Decimal> = [("a".to_string(), dec!(2)), ("b".to_string(), dec!(3))]
+        .iter()
+        .cloned()
+        .collect();
+    let closure = |name: &str| {
+        if name.starts_with('c') {
+            Some(dec!(4))
+        } else {
+            None
+        }
+    };
+
+    assert_eq!(Context::get(&btree, "a"), Some(dec!(1)));
+    assert_eq!(Context::get(&btree, "b"), None);
+    assert_eq!(Context::get(&hash, "b"), Some(dec!(3)));
+    assert_eq!(closure.get("cat"), Some(dec!(4)));
+    assert_eq!(closure.get("dog"), None);
+
+    let layered = Layered::new(&btree, &hash);
+    assert_eq!(layered.get("a"), Some(dec!(1)));
+    assert_eq!(layered.get("b"), Some(dec!(3)));
+    assert_eq!(layered.get("c"), None);
+    let layered = Layered::new(&layered, &closure);
+    assert_eq!(layered.get("a"), Some(dec!(1)));
+    assert_eq!(layered.get("c"), Some(dec!(4)));
+}
+
+#[test]
+fn test_resolver_is_only_called_for_missing_variables() {
+    let context: BTreeMap<String, Decimal> = [("a".to_string(), dec!(1))].iter().cloned().collect();
+    let calls = std::cell::RefCell::new(Vec::new());
+    let resolver = |name: &str| {
+        calls.borrow_mut().push(name.to_string());
+        match name {
+            "b" => Ok(Some(dec!(2))),
+            "broken" => Err("timed out".to_string()),
+            _ => Ok(None),
+        }
+    };
+    let context = WithResolver::new(&context, resolver);
+
+    assert_eq!(context.resolve("a"), Ok(Some(dec!(1))));
+    assert_eq!(context.resolve("b"), Ok(Some(dec!(2))));
+    assert_eq!(context.resolve("c"), Ok(None));
+    assert_eq!(context.resolve("broken"), Err("timed out".to_string()));
+    assert_eq!(context.get("broken"), None);
+    assert_eq!(*calls.borrow(), vec!["b", "c", "broken", "broken"]);
+
+    // an error from either layer stops the lookup
+    let fallback: BTreeMap<String, Decimal> =
+        [("broken".to_string(), dec!(5))].iter().cloned().collect();
+    let layered = Layered::new(&context, &fallback);
+    assert_eq!(layered.resolve("broken"), Err("timed out".to_string()));
+    assert_eq!(layered.resolve("a"), Ok(Some(dec!(1))));
+}
+
+#[test]
+fn test_scopes() {
+    let base: BTreeMap<String, Decimal> = [("a".to_string(), dec!(1)), ("b".to_string(), dec!(2))]
+        .iter()
+        .cloned()
+        .collect();
+    let region: HashMap<String, Decimal> =
+        [("b".to_string(), dec!(20)), ("c".to_string(), dec!(30))]
+            .iter()
+            .cloned()
+            .collect();
+    let customer = |name: &str| if name == "c" { Some(dec!(300)) } else { None };
+
+    let root = Scope::new("base", &base);
+    let middle = root.child("region", &region);
+    let leaf = middle.child("customer", &customer);
+
+    assert_eq!(leaf.lookup("a"), Some((dec!(1), "base")));
+    assert_eq!(leaf.lookup("b"), Some((dec!(20), "region")));
+    assert_eq!(leaf.lookup("c"), Some((dec!(300), "customer")));
+    assert_eq!(leaf.lookup("d"), None);
+    assert_eq!(middle.source("c"), Some("region"));
+    assert_eq!(root.source("c"), None);
+    assert_eq!(leaf.name(), "customer");
+    assert_eq!(leaf.parent().map(|parent| parent.name()), Some("region"));
+    assert_eq!(leaf.resolve("b"), Ok(Some(dec!(20))));
+
+    // the scopes can be shared between evaluations with different overrides
+    let other_customer = |name: &str| if name == "a" { Some(dec!(-1)) } else { None };
+    let other = middle.child("other customer", &other_customer);
+    assert_eq!(other.lookup("a"), Some((dec!(-1), "other customer")));
+    assert_eq!(leaf.lookup("a"), Some((dec!(1), "base")));
+}
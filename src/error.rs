@@ -0,0 +1,176 @@
+use std::fmt;
+
+/// The ways evaluating an expression can fail.
+///
+/// The variants for a single expression carry the offending token and the character offset (zero
+/// based) at which it starts in the expression, so callers can point the user at the problem
+/// without parsing the message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExpressurError {
+    /// An identifier that is neither a number nor a variable in the context.
+    UnknownVariable { token: String, position: usize },
+    /// A character or token that is not valid at that point in the expression.
+    SyntaxError { token: String, position: usize },
+    /// A parenthesis that has no matching partner.
+    UnbalancedParentheses { token: String, position: usize },
+    /// A division or remainder with a zero divisor.
+    DivisionByZero { token: String, position: usize },
+    /// A result too large (or too small) to be represented as a Decimal.
+    Overflow { token: String, position: usize },
+    /// An operator the evaluator does not know how to apply. Expressions are checked when they are
+    /// compiled, so evaluating one no longer returns it; it is kept for compatibility.
+    UnknownOperator { token: String, position: usize },
+    /// A function call to a function that does not exist.
+    UnknownFunction { token: String, position: usize },
+    /// A function call with more or fewer arguments than the function accepts.
+    WrongArgumentCount {
+        token: String,
+        position: usize,
+        arguments: usize,
+    },
+    /// A function argument or operand outside of its domain, such as the square root or a fractional power of a negative number.
+    InvalidArgument { token: String, position: usize },
+    /// A custom function that returned an error, with the message it returned.
+    FunctionFailed {
+        token: String,
+        position: usize,
+        message: String,
+    },
+    /// A variable whose resolver returned an error, with the message it returned.
+    ResolverFailed {
+        token: String,
+        position: usize,
+        message: String,
+    },
+    /// An expression in a set that references itself, directly or through other expressions.
+    /// `cycle` is the path of references, such as ["a", "b", "a"], and `blocked` lists the other
+    /// expressions that could not be evaluated only because they depend on the cycle.
+    CircularReference {
+        cycle: Vec<String>,
+        blocked: Vec<String>,
+    },
+}
+
+impl fmt::Display for ExpressurError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpressurError::UnknownVariable { token, position } => {
+                write!(f, "Unknown variable: {} at position {}", token, position)
+            }
+            ExpressurError::SyntaxError { token, position } => {
+                write!(f, "Unexpected token: {} at position {}", token, position)
+            }
+            ExpressurError::UnbalancedParentheses { token, position } => {
+                write!(
+                    f,
+                    "Unbalanced parenthesis: {} at position {}",
+                    token, position
+                )
+            }
+            ExpressurError::DivisionByZero { token, position } => {
+                write!(f, "Division by zero: {} at position {}", token, position)
+            }
+            ExpressurError::Overflow { token, position } => {
+                write!(f, "Overflow: {} at position {}", token, position)
+            }
+            ExpressurError::UnknownOperator { token, position } => {
+                write!(f, "Unknown operator: {} at position {}", token, position)
+            }
+            ExpressurError::UnknownFunction { token, position } => {
+                write!(f, "Unknown function: {} at position {}", token, position)
+            }
+            ExpressurError::WrongArgumentCount {
+                token,
+                position,
+                arguments,
+            } => write!(
+                f,
+                "Wrong number of arguments: {} called with {} at position {}",
+                token, arguments, position
+            ),
+            ExpressurError::InvalidArgument { token, position } => {
+                write!(f, "Invalid argument: {} at position {}", token, position)
+            }
+            ExpressurError::FunctionFailed {
+                token,
+                position,
+                message,
+            } => {
+                write!(
+                    f,
+                    "Function {} failed at position {}: {}",
+                    token, position, message
+                )
+            }
+            ExpressurError::ResolverFailed {
+                token,
+                position,
+                message,
+            } => {
+                write!(
+                    f,
+                    "Resolving {} failed at position {}: {}",
+                    token, position, message
+                )
+            }
+            ExpressurError::CircularReference { cycle, blocked } => {
+                write!(f, "Circular reference: {}", cycle.join(" -> "))?;
+                if !blocked.is_empty() {
+                    write!(f, " blocks {}", blocked.join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExpressurError {}
+
+/// Why an expression in a set could not be evaluated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FailureReason {
+    /// The variable the evaluation stopped at, which is neither in the context nor the name of
+    /// another expression in the set. Any other missing variables are reported once it is found.
+    MissingVariables(Vec<String>),
+    /// The other expressions in the set that the expression needs and that could not be evaluated
+    /// themselves: the one the evaluation stopped at or, for an expression waiting on a cycle,
+    /// those it references.
+    BlockedBy(Vec<String>),
+    /// The error that stopped the expression, such as a syntax error, a math error or a circular
+    /// reference.
+    Error(ExpressurError),
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureReason::MissingVariables(names) => {
+                write!(f, "Missing variables: {}", names.join(", "))
+            }
+            FailureReason::BlockedBy(names) => {
+                write!(f, "Blocked by failed expressions: {}", names.join(", "))
+            }
+            FailureReason::Error(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// An expression in a set that could not be evaluated, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpressionFailure {
+    /// The name of the expression.
+    pub name: String,
+    /// The source text of the expression.
+    pub expression: String,
+    pub reason: FailureReason,
+}
+
+impl fmt::Display for ExpressionFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} = {}: {}", self.name, self.expression, self.reason)
+    }
+}
+
+impl std::error::Error for ExpressionFailure {}
@@ -1,22 +1,128 @@
-use rust_decimal::MathematicalOps;
+use serde::{Deserialize, Serialize};
+
+use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::sync::Arc;
 
+use crate::compiled::CompiledExpression;
+use crate::error::{ExpressionFailure, ExpressurError, FailureReason};
+use crate::explain::{explain_compiled, explanation_set, Trace};
+use crate::functions::{checked_power, Arity, CustomFunction};
+use crate::graph::{evaluation_order, DependencyGraph};
 use crate::prelude::*;
 use crate::tokenizer::*;
 
-const SUBEXPRESSION_PRECEDENCE: i32 = 1000;
+/// The binary operators of the expression language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+    Not,
+    /// A prefix "-", as in -a.
+    Negate,
+    /// A prefix "+", as in +a, which leaves its operand unchanged.
+    Plus,
+    /// The ternary `condition ? then : else`.
+    Conditional,
+    Power,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+impl Operator {
+    pub(crate) fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Equal => "=",
+            Operator::NotEqual => "!=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::And => "and",
+            Operator::Or => "or",
+            Operator::Not => "not",
+            Operator::Negate => "-",
+            Operator::Plus => "+",
+            Operator::Conditional => "?",
+            Operator::Power => "^",
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Remainder => "%",
+        }
+    }
+
+    /// True for prefix operators, which take a single operand.
+    pub(crate) fn is_unary(&self) -> bool {
+        matches!(self, Operator::Not | Operator::Negate | Operator::Plus)
+    }
+}
+
+/// Which side a chain of operators with the same precedence groups from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Associativity {
+    /// 1 - 2 - 3 is (1 - 2) - 3
+    Left,
+    /// 2 ^ 3 ^ 2 is 2 ^ (3 ^ 2)
+    Right,
+}
+
+/// How "^" is parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PowerPrecedence {
+    /// "^" binds tighter than every other binary operator and groups from the right, as in
+    /// mathematics: 1 + 2 ^ 2 equals 5 and 2 ^ 3 ^ 2 equals 512.
+    #[default]
+    Standard,
+    /// The behaviour of earlier versions: "^" binds looser than "+" and "-" and groups from the
+    /// left, so 1 + 2 ^ 2 equals 9 and 2 ^ 3 ^ 2 equals 64.
+    Legacy,
+}
+
+/// The precedence, associativity and meaning of an operator token.
+pub(crate) fn operator_precedence(
+    op: &str,
+    power: PowerPrecedence,
+) -> Option<(i32, Associativity, Operator)> {
+    use Associativity::*;
+    match op {
+        "or" | "||" => Some((4, Left, Operator::Or)),
+        "and" | "&&" => Some((6, Left, Operator::And)),
+        "not" | "!" => Some((8, Right, Operator::Not)),
+        "=" => Some((10, Left, Operator::Equal)),
+        "!=" | "<>" => Some((10, Left, Operator::NotEqual)),
+        "<" => Some((20, Left, Operator::Less)),
+        "<=" => Some((20, Left, Operator::LessOrEqual)),
+        ">" => Some((20, Left, Operator::Greater)),
+        ">=" => Some((20, Left, Operator::GreaterOrEqual)),
+        "^" if power == PowerPrecedence::Legacy => Some((40, Left, Operator::Power)),
+        "+" => Some((50, Left, Operator::Add)),
+        "-" => Some((50, Left, Operator::Subtract)),
+        "*" => Some((80, Left, Operator::Multiply)),
+        "/" => Some((80, Left, Operator::Divide)),
+        "%" => Some((80, Left, Operator::Remainder)),
+        "^" => Some((90, Right, Operator::Power)),
+        _ => None,
+    }
+}
 
-fn operator_precedence(op: &str) -> Option<(i32, char)> {
+/// The precedence and meaning of a token that can be a prefix operator as well as a binary one,
+/// when it appears where an operand is expected. Prefix signs bind tighter than "^", like a sign
+/// written directly on a number, so -a ^ 2 is (-a) ^ 2.
+pub(crate) fn prefix_operator_precedence(op: &str) -> Option<(i32, Operator)> {
     match op {
-        "=" => Some((10, '=')),
-        "^" => Some((40, '^')),
-        "+" => Some((50, '+')),
-        "-" => Some((50, '-')),
-        "*" => Some((80, '*')),
-        "/" => Some((80, '/')),
-        "%" => Some((80, '%')),
-        "(" => Some((SUBEXPRESSION_PRECEDENCE, char::default())),
-        ")" => Some((SUBEXPRESSION_PRECEDENCE, char::default())),
+        "-" => Some((100, Operator::Negate)),
+        "+" => Some((100, Operator::Plus)),
         _ => None,
     }
 }
@@ -24,13 +130,15 @@ fn operator_precedence(op: &str) -> Option<(i32, char)> {
 ///
 /// # Arguments
 /// expression: The expression to evaluate
-/// context: A BTreeMap of variables and their values that can be used in the expression.
+/// context: The variables that can be used in the expression and their values, such as a BTreeMap or HashMap of names
+/// to values or a closure. See `Context`.
 ///
 /// # Returns
 /// The result of the expression as a Decimal.
 ///
 /// # Errors
-/// If the expression contains unknown variables or is not a valid arithemetic expression, an error is returned.
+/// If the expression contains unknown variables or is not a valid arithemetic expression, an ExpressurError is returned
+/// identifying the offending token and its character offset.
 ///
 /// # PseudoGrammar
 ///
@@ -40,9 +148,15 @@ fn operator_precedence(op: &str) -> Option<(i32, char)> {
 ///
 /// *token* := *identifier* | *number*
 ///
-/// *operator* := [*/+-%^=]
+/// *operator* := [*/+-%^=<>] | <= | >= | != | <> | && | || | and | or
+///
+/// *expression* := [not|!|-|+] *expression*
+///
+/// *expression* := *expression* ? *expression* : *expression*
+///
+/// *function* := *identifier*([*expression*[, *expression*]*])
 ///
-/// *expression* := [(]*expression*|*token* *operator* *expression*|*token*[)]
+/// *expression* := [(]*expression*|*token* *operator* *expression*|*token*|*function*[)]
 ///
 /// Expressur handles all numbers as Base-10 decimals. This will meet most end users' expectations for most scenarios.///
 ///
@@ -50,11 +164,42 @@ fn operator_precedence(op: &str) -> Option<(i32, char)> {
 ///
 /// - "+" - addition (1 + 1 equals 2)
 /// - "-" - subtraction (2 - 2 equals 0)
+/// - "-" before an operand - negation (-a, -(1 + 2) equals -3, 2 * -a)
 /// - "*" - multiplication (3 * 3 equals 9)
 /// - "/" - division (4 / 4 equals 1)
 /// - "%" - remainder (5%2 equals 1)
-/// - "^" - power (6^6 equals 46656)
+/// - "^" - power (6^6 equals 46656); zero to a negative power is a division by zero, and a negative number can only be raised to a whole power
 /// - "=" - equals (7=7 equals 1 [true], 7=9 equals 0 [false])
+/// - "!=" or "<>" - not equals (7!=9 equals 1, 7<>7 equals 0)
+/// - "<" - less than (7<9 equals 1)
+/// - "<=" - less than or equal (7<=7 equals 1)
+/// - ">" - greater than (7>9 equals 0)
+/// - ">=" - greater than or equal (9>=7 equals 1)
+/// - "and" or "&&" - logical and (1 and 0 equals 0)
+/// - "or" or "||" - logical or (1 or 0 equals 1)
+/// - "not" or "!" - logical not (not 0 equals 1)
+/// - "? :" - conditional (1 > 0 ? 2 : 3 equals 2)
+///
+/// "^" is evaluated before "*", "/" and "%", which are evaluated before "+" and "-". Chains of "^" group from the right, so 2 ^ 3 ^ 2 equals 512, and all other chains of equal precedence group from the left. A "-" or "+" in front of an operand is applied before "^", like a sign written directly on a number, so -2 ^ 2 and -a ^ 2 with a = 2 both equal 4. Earlier versions evaluated "^" after "+" and "-" and grouped it from the left; an `Evaluator` with `PowerPrecedence::Legacy` keeps that behaviour.
+///
+/// Comparisons are evaluated after arithmetic, and "<", "<=", ">", ">=" before "=", "!=", "<>", so 1 + 1 > 1 = 1 equals 1.
+///
+/// The logical operators treat 0 as false and any other value as true, and return 1 or 0. They are evaluated after comparisons, "not" first, then "and", then "or". The right operand of "and" and "or" is only evaluated when the left operand does not decide the result, so b != 0 and a / b > 1 equals 0 rather than failing when b is 0.
+///
+/// The conditional is evaluated last, and groups from the right, so a ? b : c ? d : e is a ? b : (c ? d : e). Like "if", it only evaluates the branch the condition picks.
+///
+/// ## Functions supported
+///
+/// - "if" - the second argument if the first is true, otherwise the third; only the chosen one is evaluated (if(1 > 0, 2, 1 / 0) equals 2)
+/// - "min" - smallest of one or more values (min(3, 1, 2) equals 1)
+/// - "max" - largest of one or more values (max(3, 1, 2) equals 3)
+/// - "abs" - absolute value (abs(-2) equals 2)
+/// - "round" - rounds half away from zero, to a whole number or to a number of decimal places (round(2.5) equals 3, round(1.234, 2) equals 1.23)
+/// - "floor" - largest whole number not greater than the value (floor(1.5) equals 1)
+/// - "ceil" - smallest whole number not less than the value (ceil(1.5) equals 2)
+/// - "sqrt" - square root (sqrt(16) equals 4)
+/// - "ln" - natural logarithm (ln(1) equals 0)
+/// - "exp" - e raised to the value (exp(0) equals 1); results too close to zero for a Decimal, here and from "^", round to 0
 ///
 /// # Examples
 ///
@@ -67,51 +212,33 @@ fn operator_precedence(op: &str) -> Option<(i32, char)> {
 /// let context = std::collections::BTreeMap::new();
 /// assert_eq!(evaluate_expression(expression, &context).unwrap(), expected);
 /// ```
-
 pub fn evaluate_expression(
     expression: &str,
-    context: &BTreeMap<String, Decimal>,
-) -> Result<Decimal, String> {
-    let mut stack: Vec<String> = Vec::new();
-    let mut q = reverse_polish_notate(expression.to_string());
-    while !q.is_empty() {
-        let next = q.pop_front().unwrap();
-        let precedence = operator_precedence(&next);
-        match precedence {
-            Some((_, _)) => {
-                let y = stack.pop().unwrap();
-                let x = stack.pop().unwrap();
-                let x_val = get_val(context, &x);
-                let y_val = get_val(context, &y);
-                if x_val.is_none() || y_val.is_none() {
-                    return Err(format!("Unknown variables: {} and {}", x, y));
-                } else if x_val.is_none() {
-                    return Err(format!("Unknown variable: {}", x));
-                } else if y_val.is_none() {
-                    return Err(format!("Unknown variable: {}", y));
-                }
-
-                let result =
-                    evaluate_operator(x_val.unwrap(), y_val.unwrap(), precedence.unwrap().1);
-                stack.push(result.to_string());
-            },
-            None => stack.push(next),
-        }
-    }
-    Ok(stack.pop().unwrap().parse::<Decimal>().unwrap())
+    context: &impl Context,
+) -> Result<Decimal, ExpressurError> {
+    Evaluator::new().evaluate_expression(expression, context)
 }
 
 /// Evaluates a list of arithmetic expressions and returns the results. If any expressions cannot be evaluated, they are returned in the error.
 ///
+/// The expressions are evaluated in dependency order, so each one is evaluated once, after the expressions it references.
+///
 /// # Arguments
 /// expressions: A BTreeMap of expressions to evaluate. The key is the name of the expression and the value is the expression itself. A value can be another expression.
-/// context: A BTreeMap of variables and their values that can be used in the expressions.
+/// context: The variables that can be used in the expressions and their values, such as a BTreeMap or HashMap of names
+/// to values or a closure. See `Context`.
 ///
 /// # Returns
-/// A dictionary of the results of the expressions as Decimals. This will also contain the context variables.
+/// A dictionary of the results of the expressions as Decimals. This will also contain the context variables the
+/// expressions read.
 ///  
 /// # Errors
-/// If any expressions contain unknown variables, an error is returned with the list of expressions that could not be evaluated.
+/// If any expressions cannot be evaluated, an error is returned listing each of them, with its name, its source text
+/// and the reason it failed:
+/// - the variable it stopped at because it is neither in the context nor the name of another expression,
+/// - the other expression it stopped at because that expression could not be evaluated itself,
+/// - or the ExpressurError that stopped it, such as a syntax error, a division by zero or a circular reference naming
+///   the cycle.
 ///
 /// # Examples
 ///
@@ -138,168 +265,859 @@ pub fn evaluate_expression(
 /// ```
 pub fn evaluate_expressions(
     expressions: &BTreeMap<String, String>,
-    context: &BTreeMap<String, Decimal>,
-) -> Result<BTreeMap<String, Decimal>, Vec<(String, String)>> {
-    // need to change to accept a dictionary of expressions and return those.
-    let mut results: BTreeMap<String, Decimal> = context.clone();
-    let mut expressions_to_evaluate: Vec<(String, String)> = expressions
-        .iter()
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect();
+    context: &impl Context,
+) -> Result<BTreeMap<String, Decimal>, Vec<ExpressionFailure>> {
+    Evaluator::new().evaluate_expressions(expressions, context)
+}
+
+/// The outcome of evaluating a set of expressions: every result that could be computed, and every expression that
+/// could not be evaluated with the reason why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EvaluationReport {
+    /// The results of the expressions that were evaluated. This will also contain the context variables the
+    /// expressions read.
+    pub results: BTreeMap<String, Decimal>,
+    /// The expressions that could not be evaluated, ordered by name.
+    pub failures: Vec<ExpressionFailure>,
+    /// How each result was produced, when the evaluator records provenance. See
+    /// `Evaluator::set_record_provenance`.
+    pub provenance: Option<ProvenanceMap>,
+}
+
+impl EvaluationReport {
+    /// True when every expression was evaluated.
+    pub fn is_complete(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The results if every expression was evaluated, otherwise the failures.
+    pub fn into_result(self) -> Result<BTreeMap<String, Decimal>, Vec<ExpressionFailure>> {
+        if self.is_complete() {
+            Ok(self.results)
+        } else {
+            Err(self.failures)
+        }
+    }
+}
+
+/// What produced the result of an expression: the formula, the values it read and when it was evaluated. It can be
+/// serialized, with serde, to be stored alongside the results.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The source text of the expression.
+    pub formula: String,
+    /// The variables the expression read, whether context variables or the results of other expressions, and the
+    /// values it used. Operands skipped by "and", "or" or a conditional were not read.
+    pub inputs: BTreeMap<String, Decimal>,
+    /// The position of the expression in the evaluation order, starting at 0. Every expression is evaluated after
+    /// the expressions it reads, and only the expressions that were evaluated successfully are numbered.
+    pub order: usize,
+}
+
+/// The provenance of each result of a set of expressions, by the expression's name.
+pub type ProvenanceMap = BTreeMap<String, Provenance>;
+
+/// Evaluates a list of arithmetic expressions like `evaluate_expressions`, and records the provenance of each result.
+///
+/// # Errors
+/// The same failures as `evaluate_expressions`.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+/// let mut expressions: BTreeMap<String, String> = BTreeMap::new();
+/// expressions.insert("aplusb".to_string(), "a + b".to_string());
+/// expressions.insert("doubled".to_string(), "aplusb * 2".to_string());
+///
+/// let mut context = BTreeMap::new();
+/// context.insert("a".to_string(), dec!(1));
+/// context.insert("b".to_string(), dec!(2));
+///
+/// let (results, provenance) = evaluate_expressions_with_provenance(&expressions, &context).unwrap();
+/// assert_eq!(results["doubled"], dec!(6));
+/// assert_eq!(provenance["doubled"].formula, "aplusb * 2");
+/// assert_eq!(provenance["doubled"].inputs, BTreeMap::from([("aplusb".to_string(), dec!(3))]));
+/// assert_eq!(provenance["doubled"].order, 1);
+/// ```
+pub fn evaluate_expressions_with_provenance(
+    expressions: &BTreeMap<String, String>,
+    context: &impl Context,
+) -> Result<(BTreeMap<String, Decimal>, ProvenanceMap), Vec<ExpressionFailure>> {
+    Evaluator::new().evaluate_expressions_with_provenance(expressions, context)
+}
+
+/// Evaluates a list of arithmetic expressions like `evaluate_expressions`, but keeps the results of the expressions
+/// that could be evaluated when others fail.
+///
+/// # Arguments
+/// expressions: A BTreeMap of expressions to evaluate. The key is the name of the expression and the value is the expression itself. A value can be another expression.
+/// context: The variables that can be used in the expressions and their values, such as a BTreeMap or HashMap of names
+/// to values or a closure. See `Context`.
+///
+/// # Returns
+/// An EvaluationReport with the results of the expressions that were evaluated, and the context variables they read,
+/// and the failures of those that were not, with the same reasons `evaluate_expressions` gives.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+/// let mut expressions: BTreeMap<String, String> = BTreeMap::new();
+/// expressions.insert("aplusb".to_string(), "a + b".to_string());
+/// expressions.insert("ratio".to_string(), "a / (b - 2)".to_string());
+///
+/// let mut context = BTreeMap::new();
+/// context.insert("a".to_string(), dec!(1.));
+/// context.insert("b".to_string(), dec!(2.));
+///
+/// let report = evaluate_expressions_partial(&expressions, &context);
+///
+/// assert_eq!(report.results["aplusb"], dec!(3.));
+/// assert_eq!(report.failures.len(), 1);
+/// assert_eq!(report.failures[0].name, "ratio");
+/// ```
+pub fn evaluate_expressions_partial(
+    expressions: &BTreeMap<String, String>,
+    context: &impl Context,
+) -> EvaluationReport {
+    Evaluator::new().evaluate_expressions_partial(expressions, context)
+}
+
+/// Evaluates expressions that can call custom functions written in Rust, as well as the built-in functions.
+///
+/// The free functions `evaluate_expression`, `evaluate_expressions` and `evaluate_expressions_partial` are equivalent to
+/// calling the same methods on an Evaluator with no custom functions registered.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+/// let mut evaluator = Evaluator::new();
+/// evaluator.register_function("tier_rate", Arity::Fixed(1), |args| {
+///     Ok(if args[0] > dec!(1000) { dec!(0.2) } else { dec!(0.1) })
+/// });
+///
+/// let mut context = BTreeMap::new();
+/// context.insert("income".to_string(), dec!(2000));
+/// assert_eq!(evaluator.evaluate_expression("income * tier_rate(income)", &context).unwrap(), dec!(400));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Evaluator {
+    pub(crate) functions: BTreeMap<String, Arc<CustomFunction>>,
+    pub(crate) power_precedence: PowerPrecedence,
+    pub(crate) record_provenance: bool,
+}
+
+impl Evaluator {
+    /// Creates an evaluator with only the built-in functions.
+    pub fn new() -> Evaluator {
+        Evaluator::default()
+    }
+
+    /// Registers a function that expressions can call by name. A function registered with the name of a built-in
+    /// function, or of a previously registered function, replaces it.
+    ///
+    /// # Arguments
+    /// name: The name expressions call the function by.
+    /// arity: How many arguments the function accepts. Calls with any other number are rejected when the expression is compiled.
+    /// function: The function itself. It is given the values of the arguments, and an error message it returns is
+    /// reported as an ExpressurError::FunctionFailed.
+    pub fn register_function<F>(&mut self, name: &str, arity: Arity, function: F)
+    where
+        F: Fn(&[Decimal]) -> Result<Decimal, String> + Send + Sync + 'static,
+    {
+        self.functions.insert(
+            name.to_string(),
+            Arc::new(CustomFunction {
+                name: name.to_string(),
+                arity,
+                function: Box::new(function),
+            }),
+        );
+    }
+
+    /// Chooses how "^" is parsed. Expressions written for earlier versions, where "^" was applied after "+" and "-"
+    /// and grouped from the left, keep their old results with PowerPrecedence::Legacy.
+    pub fn set_power_precedence(&mut self, power_precedence: PowerPrecedence) {
+        self.power_precedence = power_precedence;
+    }
+
+    /// Chooses whether `evaluate_expressions_partial` records the provenance of each result in its report. It is off by
+    /// default, as recording which values each expression read slows evaluation down.
+    pub fn set_record_provenance(&mut self, record_provenance: bool) {
+        self.record_provenance = record_provenance;
+    }
+
+    /// Parses an expression, which may call this evaluator's custom functions, into a CompiledExpression.
+    pub fn compile(&self, expression: &str) -> Result<CompiledExpression, ExpressurError> {
+        CompiledExpression::compile_with(expression, self)
+    }
+
+    /// Evaluates an arithmetic expression like `evaluate_expression`, with this evaluator's custom functions.
+    pub fn evaluate_expression(
+        &self,
+        expression: &str,
+        context: &impl Context,
+    ) -> Result<Decimal, ExpressurError> {
+        self.compile(expression)?.eval(context)
+    }
+
+    /// Evaluates and explains an arithmetic expression like `explain_expression`, with this evaluator's custom
+    /// functions.
+    pub fn explain_expression(
+        &self,
+        expression: &str,
+        context: &impl Context,
+    ) -> Result<Explanation, ExpressurError> {
+        explain_compiled(&self.compile(expression)?, None, expression, context)
+    }
 
-    loop {
-        let mut were_any_found = false;
-        let mut uncalculated_expressions: BTreeMap<String, String> = BTreeMap::new();
+    /// Evaluates and explains a list of arithmetic expressions like `explain_expressions`, with this evaluator's
+    /// custom functions.
+    pub fn explain_expressions(
+        &self,
+        expressions: &BTreeMap<String, String>,
+        context: &impl Context,
+    ) -> Result<ExplanationSet, Vec<ExpressionFailure>> {
+        let evaluation = self.evaluate_traced(expressions, context, || Some(Trace::default()));
+        if !evaluation.failures.is_empty() {
+            return Err(failure_list(expressions, evaluation.failures));
+        }
+        let explanations = evaluation
+            .traces
+            .into_iter()
+            .map(|(name, trace)| {
+                let value = evaluation.results[&name];
+                trace.into_explanation(Some(&name), &expressions[&name], value)
+            })
+            .collect();
+        Ok(explanation_set(explanations))
+    }
+
+    /// Evaluates a list of arithmetic expressions like `evaluate_expressions`, with this evaluator's custom functions.
+    pub fn evaluate_expressions(
+        &self,
+        expressions: &BTreeMap<String, String>,
+        context: &impl Context,
+    ) -> Result<BTreeMap<String, Decimal>, Vec<ExpressionFailure>> {
+        self.evaluate_expressions_partial(expressions, context)
+            .into_result()
+    }
+
+    /// Evaluates a list of arithmetic expressions like `evaluate_expressions_with_provenance`, with this evaluator's
+    /// custom functions.
+    pub fn evaluate_expressions_with_provenance(
+        &self,
+        expressions: &BTreeMap<String, String>,
+        context: &impl Context,
+    ) -> Result<(BTreeMap<String, Decimal>, ProvenanceMap), Vec<ExpressionFailure>> {
+        let mut evaluator = self.clone();
+        evaluator.record_provenance = true;
+        let report = evaluator.evaluate_expressions_partial(expressions, context);
+        if report.is_complete() {
+            Ok((report.results, report.provenance.unwrap_or_default()))
+        } else {
+            Err(report.failures)
+        }
+    }
+
+    /// Evaluates a list of arithmetic expressions like `evaluate_expressions_partial`, with this evaluator's custom
+    /// functions.
+    pub fn evaluate_expressions_partial(
+        &self,
+        expressions: &BTreeMap<String, String>,
+        context: &impl Context,
+    ) -> EvaluationReport {
+        // the context variables the expressions read are returned with their results
+        let context = ReadValues::new(context);
+        let evaluation = self.evaluate_traced(expressions, &context, || {
+            self.record_provenance.then(Trace::variables_only)
+        });
+        let provenance = self.record_provenance.then(|| {
+            // the traces are in evaluation order, and the expressions that failed are skipped
+            evaluation
+                .traces
+                .into_iter()
+                .filter(|(name, _)| evaluation.results.contains_key(name))
+                .enumerate()
+                .map(|(index, (name, trace))| {
+                    let provenance = Provenance {
+                        formula: expressions[&name].to_string(),
+                        inputs: trace.into_variables(),
+                        order: index,
+                    };
+                    (name, provenance)
+                })
+                .collect()
+        });
+
+        let mut results = evaluation.results;
+        results.append(&mut context.into_values());
+        EvaluationReport {
+            results,
+            failures: failure_list(expressions, evaluation.failures),
+            provenance,
+        }
+    }
+
+    /// Evaluates a list of arithmetic expressions, passing each expression that is evaluated the
+    /// trace `new_trace` returns, if any.
+    fn evaluate_traced(
+        &self,
+        expressions: &BTreeMap<String, String>,
+        context: &impl Context,
+        new_trace: impl Fn() -> Option<Trace>,
+    ) -> TracedEvaluation {
+        let mut results: BTreeMap<String, Decimal> = BTreeMap::new();
+        let mut failures: BTreeMap<String, FailureReason> = BTreeMap::new();
+
+        let mut compiled_expressions: BTreeMap<&str, CompiledExpression> = BTreeMap::new();
+        for (name, expression) in expressions {
+            match self.compile(expression) {
+                Ok(compiled) => {
+                    compiled_expressions.insert(name, compiled);
+                }
+                Err(error) => {
+                    failures.insert(name.to_string(), FailureReason::Error(error));
+                }
+            }
+        }
 
-        for expression in expressions_to_evaluate {
-            let result = evaluate_expression(expression.1.as_str(), &results);
-            match result {
+        // every expression is evaluated after the expressions it references, so a single pass is
+        // enough. Expressions caught in, or waiting on, a cycle are never evaluated.
+        let (order, blocked) = evaluation_order(&compiled_expressions);
+        let mut traces = Vec::new();
+        for name in order {
+            let mut trace = new_trace();
+            match evaluate_member(
+                &compiled_expressions[name],
+                expressions,
+                &results,
+                context,
+                trace.as_mut(),
+            ) {
                 Ok(value) => {
-                    results.insert(expression.0, value);
-                    were_any_found = true;
+                    results.insert(name.to_string(), value);
                 }
-                _ => {
-                    uncalculated_expressions.insert(expression.0, expression.1);
+                Err(reason) => {
+                    failures.insert(name.to_string(), reason);
                 }
             }
+            if let Some(trace) = trace {
+                traces.push((name.to_string(), trace));
+            }
         }
-        expressions_to_evaluate = uncalculated_expressions
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_string()))
-            .collect();
-        if uncalculated_expressions.is_empty() && were_any_found {
-            break;
-        } else if !were_any_found {
-            if cfg!(debug_assertions) {
-                println!(
-                    "Ending loop. Could not evaluate: {}",
-                    uncalculated_expressions
-                        .keys()
-                        .map(|k| k.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", ")
-                );
+        // the graph is only needed to explain why the blocked expressions were not evaluated
+        if !blocked.is_empty() {
+            let blocked: Vec<String> = blocked.into_iter().map(String::from).collect();
+            let graph = DependencyGraph::new(&compiled_expressions);
+            failures.extend(graph.blocked_reasons(&blocked, &results));
+        }
+
+        TracedEvaluation {
+            results,
+            failures,
+            traces,
+        }
+    }
+}
+
+/// The outcome of evaluating a set of expressions, with the trace of each expression that was
+/// evaluated, in evaluation order.
+struct TracedEvaluation {
+    results: BTreeMap<String, Decimal>,
+    failures: BTreeMap<String, FailureReason>,
+    traces: Vec<(String, Trace)>,
+}
+
+/// The failures of a set of expressions, in order of their names, with the expressions' text.
+fn failure_list(
+    expressions: &BTreeMap<String, String>,
+    failures: BTreeMap<String, FailureReason>,
+) -> Vec<ExpressionFailure> {
+    failures
+        .into_iter()
+        .map(|(name, reason)| ExpressionFailure {
+            expression: expressions[&name].to_string(),
+            name,
+            reason,
+        })
+        .collect()
+}
+
+/// Evaluates one expression of a set against the results of the others, then the context. When the evaluation reaches
+/// a variable that cannot be found, the expression is blocked by that expression of the set, which failed, or the
+/// variable is missing from the context. The context is not asked for anything again to find out which.
+pub(crate) fn evaluate_member<C: Context>(
+    compiled: &CompiledExpression,
+    expressions: &BTreeMap<String, String>,
+    results: &BTreeMap<String, Decimal>,
+    context: &C,
+    trace: Option<&mut Trace>,
+) -> Result<Decimal, FailureReason> {
+    let values = SetContext {
+        expressions,
+        results,
+        context,
+    };
+    match compiled.run(&values, trace) {
+        Ok(value) => Ok(value),
+        // the evaluation stops at the first variable it cannot find, which is either another
+        // expression that failed or a variable the context does not have
+        Err(ExpressurError::UnknownVariable { token, .. }) => {
+            if expressions.contains_key(&token) {
+                Err(FailureReason::BlockedBy(vec![token]))
+            } else {
+                Err(FailureReason::MissingVariables(vec![token]))
             }
-            break;
-        } else if cfg!(debug_assertions) {
-            println!(
-                "Looping back. Could not evaluate: {}",
-                uncalculated_expressions
-                    .keys()
-                    .map(|k| k.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            );
         }
+        Err(error) => Err(FailureReason::Error(error)),
     }
-    if expressions_to_evaluate.is_empty() {
-        Ok(results)
-    } else {
-        Err(expressions_to_evaluate)
+}
+
+/// The values the expressions in a set can reference: the results of the other expressions, then the context. An
+/// expression's name hides any context variable with the same name, even when the expression fails.
+struct SetContext<'a, C> {
+    expressions: &'a BTreeMap<String, String>,
+    results: &'a BTreeMap<String, Decimal>,
+    context: &'a C,
+}
+
+impl<C: Context> Context for SetContext<'_, C> {
+    fn get(&self, name: &str) -> Option<Decimal> {
+        if self.expressions.contains_key(name) {
+            self.results.get(name).copied()
+        } else {
+            self.context.get(name)
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Result<Option<Decimal>, String> {
+        if self.expressions.contains_key(name) {
+            Ok(self.results.get(name).copied())
+        } else {
+            self.context.resolve(name)
+        }
     }
 }
 
-fn get_val(context: &BTreeMap<String, Decimal>, token: &String) -> Option<Decimal> {
-    match Decimal::from_str_exact(token) {
-        Ok(value) => Some(value),
-        _ => {
-            if context.contains_key(token) {
-                Some(context[token])
-            } else {
-                None
+/// A context that keeps the values it was asked for, so they can be returned with the results of a set of expressions.
+/// Each variable is only copied the first time it is read.
+struct ReadValues<'a, C> {
+    context: &'a C,
+    values: RefCell<BTreeMap<String, Decimal>>,
+}
+
+impl<'a, C: Context> ReadValues<'a, C> {
+    fn new(context: &'a C) -> ReadValues<'a, C> {
+        ReadValues {
+            context,
+            values: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn keep(&self, name: &str, value: Option<Decimal>) -> Option<Decimal> {
+        if let Some(value) = value {
+            let mut values = self.values.borrow_mut();
+            if !values.contains_key(name) {
+                values.insert(name.to_string(), value);
             }
         }
+        value
+    }
+
+    fn into_values(self) -> BTreeMap<String, Decimal> {
+        self.values.into_inner()
+    }
+}
+
+impl<C: Context> Context for ReadValues<'_, C> {
+    fn get(&self, name: &str) -> Option<Decimal> {
+        self.keep(name, self.context.get(name))
     }
+
+    fn resolve(&self, name: &str) -> Result<Option<Decimal>, String> {
+        Ok(self.keep(name, self.context.resolve(name)?))
+    }
+}
+
+/// 1 for true and 0 for false, the values comparisons and logical operators produce.
+pub(crate) fn truth(condition: bool) -> Decimal {
+    if condition {
+        dec!(1.0)
+    } else {
+        dec!(0.0)
+    }
+}
+
+pub(crate) fn evaluate_operator(
+    x: Decimal,
+    y: Decimal,
+    op: Operator,
+    position: usize,
+) -> Result<Decimal, ExpressurError> {
+    let result = match op {
+        Operator::Equal => Some(truth(x == y)),
+        Operator::NotEqual => Some(truth(x != y)),
+        Operator::Less => Some(truth(x < y)),
+        Operator::LessOrEqual => Some(truth(x <= y)),
+        Operator::Greater => Some(truth(x > y)),
+        Operator::GreaterOrEqual => Some(truth(x >= y)),
+        Operator::And => Some(truth(!x.is_zero() && !y.is_zero())),
+        Operator::Or => Some(truth(!x.is_zero() || !y.is_zero())),
+        // zero to a negative power divides by zero, and a negative number has no real
+        // fractional powers
+        Operator::Power if x.is_zero() && y < Decimal::ZERO => {
+            return Err(ExpressurError::DivisionByZero {
+                token: op.symbol().to_string(),
+                position,
+            })
+        }
+        Operator::Power if x < Decimal::ZERO && !y.fract().is_zero() => {
+            return Err(ExpressurError::InvalidArgument {
+                token: op.symbol().to_string(),
+                position,
+            })
+        }
+        Operator::Power => checked_power(x, y),
+        Operator::Add => x.checked_add(y),
+        Operator::Subtract => x.checked_sub(y),
+        Operator::Multiply => x.checked_mul(y),
+        Operator::Divide | Operator::Remainder if y.is_zero() => {
+            return Err(ExpressurError::DivisionByZero {
+                token: op.symbol().to_string(),
+                position,
+            })
+        }
+        Operator::Divide => x.checked_div(y),
+        Operator::Remainder => x.checked_rem(y),
+        Operator::Not | Operator::Negate | Operator::Plus | Operator::Conditional => {
+            return Err(ExpressurError::UnknownOperator {
+                token: op.symbol().to_string(),
+                position,
+            })
+        }
+    };
+    result.ok_or(ExpressurError::Overflow {
+        token: op.symbol().to_string(),
+        position,
+    })
 }
 
-fn evaluate_operator(x: Decimal, y: Decimal, op: char) -> Decimal {
+pub(crate) fn evaluate_unary_operator(
+    x: Decimal,
+    op: Operator,
+    position: usize,
+) -> Result<Decimal, ExpressurError> {
     match op {
-        '=' => {
-            if x == y {
-                dec!(1.0)
-            } else {
-                dec!(0.0)
+        Operator::Not => Ok(truth(x.is_zero())),
+        Operator::Negate => Ok(-x),
+        Operator::Plus => Ok(x),
+        _ => Err(ExpressurError::UnknownOperator {
+            token: op.symbol().to_string(),
+            position,
+        }),
+    }
+}
+
+/// A token of an expression in Reverse Polish Notation.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RpnToken {
+    /// A number or a variable.
+    Operand(String),
+    Operator(Operator),
+    /// A function and the number of arguments it is called with.
+    Call(String, usize),
+}
+
+/// An entry on the shunting-yard's operator stack.
+enum Pending {
+    Operator(i32, Operator),
+    LeftParenthesis,
+    /// A function whose arguments follow, and how many have been found so far.
+    Function(String, usize),
+    /// The "?" of a conditional whose ":" has not been found yet.
+    Question,
+}
+
+/// The precedence of the conditional operator, below every other operator so that each of its
+/// three operands extends as far as it can.
+const CONDITIONAL_PRECEDENCE: i32 = 2;
+
+pub(crate) fn reverse_polish_notate(
+    expression: &str,
+    power: PowerPrecedence,
+) -> Result<VecDeque<(RpnToken, usize)>, ExpressurError> {
+    let mut output: VecDeque<(RpnToken, usize)> = VecDeque::new();
+
+    let mut operator_stack: Vec<(Pending, usize)> = Vec::new();
+    // operands and operators have to alternate, so track which is expected next
+    let mut expect_operand = true;
+    let mut last_token: Option<(String, usize)> = None;
+    let mut tokens = tokenize(expression)?.into_iter().peekable();
+    // tokens span bytes but errors report characters, so count the characters before each token
+    let (mut position, mut counted) = (0, 0);
+    while let Some(token) = tokens.next() {
+        let span = token.span();
+        position += expression[counted..span.start].chars().count();
+        counted = span.start;
+        let next = &expression[span];
+        let syntax_error = || ExpressurError::SyntaxError {
+            token: next.to_string(),
+            position,
+        };
+        match token {
+            Token::LeftParenthesis(_) => {
+                if !expect_operand {
+                    return Err(syntax_error());
+                }
+                operator_stack.push((Pending::LeftParenthesis, position));
             }
-        }
-        '^' => x.powd(y),
-        '+' => x + y,
-        '-' => x - y,
-        '*' => x * y,
-        '/' => x / y,
-        '%' => x % y,
-        _ => panic!("Unknown operator: {}", op),
-    }
-}
-
-fn reverse_polish_notate(expression: String) -> VecDeque<String> {
-    let mut output: Vec<String> = Vec::new();
-
-    let mut operator_stack: Vec<(i32, String)> = Vec::new();
-    let tokens = tokenize(&expression);
-    for next in tokens {
-        let precedence_result = operator_precedence(&next);
-
-        match precedence_result {
-            Some(precedence) => {
-                if precedence.0 < SUBEXPRESSION_PRECEDENCE {
-                    while !operator_stack.is_empty()
-                        && operator_stack.last().unwrap().0 >= precedence.0
-                        && operator_stack.last().unwrap().1 != "("
-                    {
-                        let op = operator_stack.pop().unwrap().1;
-                        output.push(op);
+            Token::RightParenthesis(_) | Token::Comma(_) => {
+                let is_empty_call = next == ")"
+                    && matches!(&last_token, Some((token, _)) if token == "(")
+                    && matches!(
+                        operator_stack.as_slice(),
+                        [
+                            ..,
+                            (Pending::Function(_, _), _),
+                            (Pending::LeftParenthesis, _)
+                        ]
+                    );
+                if expect_operand && !is_empty_call {
+                    return Err(syntax_error());
+                }
+                pop_operators(&mut operator_stack, &mut output, i32::MIN);
+                match (next, operator_stack.as_mut_slice()) {
+                    (
+                        ",",
+                        [.., (Pending::Function(_, arguments), _), (Pending::LeftParenthesis, _)],
+                    ) => {
+                        *arguments += 1;
+                        expect_operand = true;
+                    }
+                    // a conditional without its ":", such as "max(a ? b, c)"
+                    (_, [.., (Pending::Question, question)]) => {
+                        return Err(ExpressurError::SyntaxError {
+                            token: "?".to_string(),
+                            position: *question,
+                        })
                     }
-                    operator_stack.push((precedence.0, next));
-                } else if next == "(" {
-                    operator_stack.push((precedence.0, next));
-                } else if next == ")" {
-                    let mut found_left_parens = false;
-                    while !operator_stack.is_empty() {
-                        let op = operator_stack.pop().unwrap().1;
-                        if op != "(" {
-                            output.push(op);
-                        } else {
-                            found_left_parens = true;
-                            break;
+                    // a comma outside of a function's arguments
+                    (",", _) => return Err(syntax_error()),
+                    (_, [.., (Pending::LeftParenthesis, _)]) => {
+                        operator_stack.pop();
+                        if let Some((Pending::Function(_, _), _)) = operator_stack.last() {
+                            if let Some((Pending::Function(name, arguments), function_position)) =
+                                operator_stack.pop()
+                            {
+                                let arguments = if is_empty_call { 0 } else { arguments + 1 };
+                                output.push_back((
+                                    RpnToken::Call(name, arguments),
+                                    function_position,
+                                ));
+                            }
                         }
+                        expect_operand = false;
                     }
-                    if !found_left_parens {
-                        panic!(
-                        "Parenthesis were not balanced in the expression {}. Missing Left Parenthesis",
-                        expression
-                    );
+                    // Missing left parenthesis
+                    _ => {
+                        return Err(ExpressurError::UnbalancedParentheses {
+                            token: next.to_string(),
+                            position,
+                        })
+                    }
+                }
+            }
+            Token::Question(_) => {
+                if expect_operand {
+                    return Err(syntax_error());
+                }
+                // conditionals are right associative, so a ? b : c ? d : e leaves the first one
+                // on the stack until its else branch is complete
+                pop_operators(&mut operator_stack, &mut output, CONDITIONAL_PRECEDENCE + 1);
+                operator_stack.push((Pending::Question, position));
+                expect_operand = true;
+            }
+            Token::Colon(_) => {
+                if expect_operand {
+                    return Err(syntax_error());
+                }
+                pop_operators(&mut operator_stack, &mut output, i32::MIN);
+                match operator_stack.last_mut() {
+                    Some((pending @ Pending::Question, _)) => {
+                        *pending = Pending::Operator(CONDITIONAL_PRECEDENCE, Operator::Conditional);
+                    }
+                    // a ":" without a "?"
+                    _ => return Err(syntax_error()),
+                }
+                expect_operand = true;
+            }
+            Token::Operator(_) => match prefix_operator_precedence(next)
+                .filter(|_| expect_operand)
+                .map(|(precedence, op)| (precedence, Associativity::Right, op))
+                .or_else(|| operator_precedence(next, power))
+            {
+                Some((precedence, _, op)) if op.is_unary() => {
+                    // a prefix operator applies to the operand that follows, so nothing before
+                    // it can be complete yet
+                    if !expect_operand {
+                        return Err(syntax_error());
+                    }
+                    operator_stack.push((Pending::Operator(precedence, op), position));
+                }
+                Some((precedence, associativity, op)) => {
+                    if expect_operand {
+                        return Err(syntax_error());
                     }
+                    // a right associative operator leaves an earlier one with the same precedence
+                    // waiting for the operand that follows
+                    let popped = match associativity {
+                        Associativity::Left => precedence,
+                        Associativity::Right => precedence + 1,
+                    };
+                    pop_operators(&mut operator_stack, &mut output, popped);
+                    operator_stack.push((Pending::Operator(precedence, op), position));
+                    expect_operand = true;
                 }
+                None => return Err(syntax_error()),
+            },
+            Token::Number(_) | Token::Identifier(_) => {
+                if !expect_operand {
+                    return Err(syntax_error());
+                }
+                if matches!(token, Token::Identifier(_))
+                    && matches!(tokens.peek(), Some(Token::LeftParenthesis(_)))
+                {
+                    // a function call, such as max(a, b)
+                    operator_stack.push((Pending::Function(next.to_string(), 0), position));
+                } else {
+                    output.push_back((RpnToken::Operand(next.to_string()), position));
+                    expect_operand = false;
+                }
+            }
+        }
+        last_token = Some((next.to_string(), position));
+    }
+    if expect_operand {
+        if let Some((token, position)) = last_token {
+            if token != "(" {
+                // the expression ends with an operator, such as "1 +"
+                return Err(ExpressurError::SyntaxError { token, position });
+            }
+        }
+    }
+    while let Some((pending, op_position)) = operator_stack.pop() {
+        match pending {
+            Pending::Operator(_, op) => output.push_back((RpnToken::Operator(op), op_position)),
+            Pending::Question => {
+                return Err(ExpressurError::SyntaxError {
+                    token: "?".to_string(),
+                    position: op_position,
+                })
+            }
+            // Missing right parenthesis
+            Pending::LeftParenthesis | Pending::Function(_, _) => {
+                return Err(ExpressurError::UnbalancedParentheses {
+                    token: "(".to_string(),
+                    position: op_position,
+                })
             }
-            None => output.push(next),
         }
     }
-    while !operator_stack.is_empty() {
-        let op = operator_stack.pop().unwrap().1;
-        if op == "(" {
-            panic!(
-                "Parenthesis were not balanced in the expression {}. Missing Right Parenthesis",
-                expression
-            );
+
+    Ok(output)
+}
+
+/// Moves the operators on top of the stack with at least the given precedence to the output.
+fn pop_operators(
+    operator_stack: &mut Vec<(Pending, usize)>,
+    output: &mut VecDeque<(RpnToken, usize)>,
+    precedence: i32,
+) {
+    while let Some((Pending::Operator(top, op), op_position)) = operator_stack.last() {
+        if *top < precedence {
+            break;
         }
-        output.push(op);
+        output.push_back((RpnToken::Operator(*op), *op_position));
+        operator_stack.pop();
     }
+}
 
-    output.iter().map(|x| x.to_string()).collect()
+#[cfg(test)]
+fn rpn_tokens(expression: &str) -> Vec<String> {
+    reverse_polish_notate(expression, PowerPrecedence::Standard)
+        .unwrap()
+        .into_iter()
+        .map(|(token, _)| match token {
+            RpnToken::Operand(operand) => operand,
+            RpnToken::Operator(op) => op.symbol().to_string(),
+            RpnToken::Call(name, arguments) => format!("{}/{}", name, arguments),
+        })
+        .collect()
 }
 
 #[test]
 fn test_reverse_polish_notate_1() {
-    let expression = "1 + 2 * 3".to_string();
+    let expression = "1 + 2 * 3";
     let expected = vec!["1", "2", "3", "*", "+"];
-    assert_eq!(reverse_polish_notate(expression), expected);
+    assert_eq!(rpn_tokens(expression), expected);
 }
 
 #[test]
 fn test_reverse_polish_notate_2() {
-    let expression = "(1 + 2) * 3".to_string();
+    let expression = "(1 + 2) * 3";
     let expected = vec!["1", "2", "+", "3", "*"];
-    assert_eq!(reverse_polish_notate(expression), expected);
+    assert_eq!(rpn_tokens(expression), expected);
+}
+
+#[test]
+fn test_reverse_polish_notate_functions() {
+    let expression = "max(1, min(a, 2) * 3, abs(-4)) + 5";
+    let expected = vec![
+        "1", "a", "2", "min/2", "3", "*", "-4", "abs/1", "max/3", "5", "+",
+    ];
+    assert_eq!(rpn_tokens(expression), expected);
+}
+
+#[test]
+fn test_reverse_polish_notate_conditionals() {
+    assert_eq!(
+        rpn_tokens("a > 1 ? b + 1 : c"),
+        vec!["a", "1", ">", "b", "1", "+", "c", "?"]
+    );
+    assert_eq!(
+        rpn_tokens("a ? b : c ? d : e"),
+        vec!["a", "b", "c", "d", "e", "?", "?"]
+    );
+    assert_eq!(
+        rpn_tokens("a ? b ? c : d : e"),
+        vec!["a", "b", "c", "d", "?", "e", "?"]
+    );
+    assert_eq!(
+        rpn_tokens("if(a, b, c) * 2"),
+        vec!["a", "b", "c", "if/3", "2", "*"]
+    );
+}
+
+#[test]
+fn test_reverse_polish_notate_prefix_operators() {
+    assert_eq!(rpn_tokens("-a"), vec!["a", "-"]);
+    assert_eq!(rpn_tokens("2 * -x"), vec!["2", "x", "-", "*"]);
+    assert_eq!(
+        rpn_tokens("-(a + b) - c"),
+        vec!["a", "b", "+", "-", "c", "-"]
+    );
+    assert_eq!(rpn_tokens("-a ^ 2"), vec!["a", "-", "2", "^"]);
+    assert_eq!(rpn_tokens("2 ^ -a"), vec!["2", "a", "-", "^"]);
+    assert_eq!(rpn_tokens("- +a"), vec!["a", "+", "-"]);
 }
 
 #[test]
@@ -398,6 +1216,25 @@ fn test_batch_evaluate_expressions() {
         ("2 / 4", dec!(0.5)),
         ("1 +555", dec!(556.)),
         ("1+ 555", dec![556.]),
+        ("max(1, 5, 3)", dec!(5.)),
+        ("min(4, -2) * 2", dec!(-4.)),
+        ("abs(1 - 3)", dec!(2.)),
+        ("round(2 / 3, 2)", dec!(0.67)),
+        ("round(2.5)", dec!(3.)),
+        ("floor(-1.5) + ceil(1.5)", dec!(0.)),
+        ("sqrt(max(16, 9))", dec!(4.)),
+        ("ln(exp(2))", dec!(2.)),
+        ("1 < 2", dec!(1.)),
+        ("2 < 1", dec!(0.)),
+        ("2 <= 2", dec!(1.)),
+        ("2 > 2", dec!(0.)),
+        ("3 >= 2", dec!(1.)),
+        ("3 != 2", dec!(1.)),
+        ("3 <> 3", dec!(0.)),
+        ("1 + 2 > 2 * 1", dec!(1.)),
+        ("2 < 3 = 1", dec!(1.)),
+        ("1 < 2 = 3 < 2", dec!(0.)),
+        ("-1 >= -1", dec!(1.)),
     ];
     for test in tests {
         let context = BTreeMap::new();
@@ -409,3 +1246,988 @@ fn test_batch_evaluate_expressions() {
         );
     }
 }
+
+#[test]
+fn test_evaluate_expression_errors() {
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(1.))].iter().cloned().collect();
+    let tests = vec![
+        (
+            "a + b",
+            ExpressurError::UnknownVariable {
+                token: "b".to_string(),
+                position: 4,
+            },
+        ),
+        (
+            "c",
+            ExpressurError::UnknownVariable {
+                token: "c".to_string(),
+                position: 0,
+            },
+        ),
+        (
+            "1 / (a - 1)",
+            ExpressurError::DivisionByZero {
+                token: "/".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "5 % 0",
+            ExpressurError::DivisionByZero {
+                token: "%".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "79228162514264337593543950335 * 2",
+            ExpressurError::Overflow {
+                token: "*".to_string(),
+                position: 30,
+            },
+        ),
+        (
+            "2 ^ 100",
+            ExpressurError::Overflow {
+                token: "^".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "(-1) ^ 0.5",
+            ExpressurError::InvalidArgument {
+                token: "^".to_string(),
+                position: 5,
+            },
+        ),
+        (
+            "0 ^ -1",
+            ExpressurError::DivisionByZero {
+                token: "^".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "(1 + 2",
+            ExpressurError::UnbalancedParentheses {
+                token: "(".to_string(),
+                position: 0,
+            },
+        ),
+        (
+            "1 + 2)",
+            ExpressurError::UnbalancedParentheses {
+                token: ")".to_string(),
+                position: 5,
+            },
+        ),
+        (
+            "1 # 2",
+            ExpressurError::SyntaxError {
+                token: "#".to_string(),
+                position: 2,
+            },
+        ),
+    ];
+    for test in tests {
+        assert_eq!(
+            evaluate_expression(test.0, &context),
+            Err(test.1),
+            "Failed to evaluate: {}",
+            test.0
+        );
+    }
+}
+
+#[test]
+fn test_evaluate_operator_unknown_operator() {
+    // the compiler never applies a prefix operator as a binary one, or the reverse
+    assert_eq!(
+        evaluate_operator(dec!(1), dec!(2), Operator::Not, 3),
+        Err(ExpressurError::UnknownOperator {
+            token: "not".to_string(),
+            position: 3
+        })
+    );
+    assert_eq!(
+        evaluate_unary_operator(dec!(1), Operator::Add, 0),
+        Err(ExpressurError::UnknownOperator {
+            token: "+".to_string(),
+            position: 0
+        })
+    );
+}
+
+#[test]
+fn test_malformed_expressions_return_errors() {
+    let context: BTreeMap<String, Decimal> = BTreeMap::new();
+    let tests = vec![
+        (
+            "1 +",
+            ExpressurError::SyntaxError {
+                token: "+".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "1 -",
+            ExpressurError::SyntaxError {
+                token: "-".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "-",
+            ExpressurError::SyntaxError {
+                token: "-".to_string(),
+                position: 0,
+            },
+        ),
+        (
+            "* 2",
+            ExpressurError::SyntaxError {
+                token: "*".to_string(),
+                position: 0,
+            },
+        ),
+        (
+            "1 2",
+            ExpressurError::SyntaxError {
+                token: "2".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "",
+            ExpressurError::SyntaxError {
+                token: "".to_string(),
+                position: 0,
+            },
+        ),
+        (
+            "  ",
+            ExpressurError::SyntaxError {
+                token: "".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "()",
+            ExpressurError::SyntaxError {
+                token: ")".to_string(),
+                position: 1,
+            },
+        ),
+        (
+            "1 (2)",
+            ExpressurError::SyntaxError {
+                token: "(".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "(1 +)",
+            ExpressurError::SyntaxError {
+                token: ")".to_string(),
+                position: 4,
+            },
+        ),
+        (
+            "1, 2",
+            ExpressurError::SyntaxError {
+                token: ",".to_string(),
+                position: 1,
+            },
+        ),
+        (
+            "max(1,)",
+            ExpressurError::SyntaxError {
+                token: ")".to_string(),
+                position: 6,
+            },
+        ),
+        (
+            "max(,1)",
+            ExpressurError::SyntaxError {
+                token: ",".to_string(),
+                position: 4,
+            },
+        ),
+        (
+            "max(1",
+            ExpressurError::UnbalancedParentheses {
+                token: "(".to_string(),
+                position: 3,
+            },
+        ),
+        (
+            "(1",
+            ExpressurError::UnbalancedParentheses {
+                token: "(".to_string(),
+                position: 0,
+            },
+        ),
+        (
+            "1 # 2",
+            ExpressurError::SyntaxError {
+                token: "#".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "größe +",
+            ExpressurError::SyntaxError {
+                token: "+".to_string(),
+                position: 6,
+            },
+        ),
+        (
+            "(größe",
+            ExpressurError::UnbalancedParentheses {
+                token: "(".to_string(),
+                position: 0,
+            },
+        ),
+        (
+            "größe)",
+            ExpressurError::UnbalancedParentheses {
+                token: ")".to_string(),
+                position: 5,
+            },
+        ),
+    ];
+    for test in tests {
+        assert_eq!(
+            evaluate_expression(test.0, &context),
+            Err(test.1),
+            "Failed to evaluate: {}",
+            test.0
+        );
+    }
+}
+
+// Property test: no input, however malformed, may panic. Every string of up to three
+// characters over a small alphabet is tried, then a deterministic pseudo-random sample of
+// longer strings.
+#[test]
+fn test_no_input_panics() {
+    let alphabet: Vec<char> = "0159.+-*/%^=<>!&|?:(), ab_#".chars().collect();
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(0.5))].iter().cloned().collect();
+    let check = |expression: &str| {
+        let result = std::panic::catch_unwind(|| evaluate_expression(expression, &context));
+        assert!(result.is_ok(), "Panicked evaluating: {:?}", expression);
+    };
+
+    let mut expressions: Vec<String> = vec![String::new()];
+    for _ in 0..3 {
+        expressions = expressions
+            .iter()
+            .flat_map(|prefix| alphabet.iter().map(move |c| format!("{}{}", prefix, c)))
+            .collect();
+        expressions.iter().for_each(|expression| check(expression));
+    }
+
+    // xorshift64, so failures are reproducible
+    let mut seed: u64 = 0x2545_F491_4F6C_DD1D;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+    for _ in 0..20_000 {
+        let len = (next() % 24) as usize;
+        let expression: String = (0..len)
+            .map(|_| alphabet[(next() % alphabet.len() as u64) as usize])
+            .collect();
+        check(&expression);
+    }
+}
+
+#[test]
+fn test_evaluate_expressions_deep_chain() {
+    let expressions: BTreeMap<String, String> = (1..200)
+        .map(|i| (format!("x{:03}", i), format!("x{:03} + 1", i - 1)))
+        .collect();
+    let context: BTreeMap<String, Decimal> =
+        [("x000".to_string(), dec!(0.))].iter().cloned().collect();
+    let results = evaluate_expressions(&expressions, &context).unwrap();
+    assert_eq!(results["x199"], dec!(199.));
+}
+
+#[test]
+fn test_evaluate_expressions_reports_math_errors() {
+    let expressions: BTreeMap<String, String> = [
+        ("ratio".to_string(), "a / b".to_string()),
+        ("scaled".to_string(), "ratio * 100".to_string()),
+        ("sum".to_string(), "a + b".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(1.)), ("b".to_string(), dec!(0.))]
+            .iter()
+            .cloned()
+            .collect();
+    let failures = evaluate_expressions(&expressions, &context).unwrap_err();
+    assert_eq!(
+        failures,
+        vec![
+            ExpressionFailure {
+                name: "ratio".to_string(),
+                expression: "a / b".to_string(),
+                reason: FailureReason::Error(ExpressurError::DivisionByZero {
+                    token: "/".to_string(),
+                    position: 2
+                }),
+            },
+            ExpressionFailure {
+                name: "scaled".to_string(),
+                expression: "ratio * 100".to_string(),
+                reason: FailureReason::BlockedBy(vec!["ratio".to_string()]),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_evaluate_expressions_reports_cycles() {
+    let expressions: BTreeMap<String, String> = [
+        ("a".to_string(), "b + 1".to_string()),
+        ("b".to_string(), "a * 2".to_string()),
+        ("c".to_string(), "b + x".to_string()),
+        ("x".to_string(), "1".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let failures = evaluate_expressions(&expressions, &BTreeMap::new()).unwrap_err();
+    let cycle_error = |cycle: &[&str]| {
+        FailureReason::Error(ExpressurError::CircularReference {
+            cycle: cycle.iter().map(|name| name.to_string()).collect(),
+            blocked: vec!["c".to_string()],
+        })
+    };
+    let reasons: Vec<(String, FailureReason)> = failures
+        .into_iter()
+        .map(|failure| (failure.name, failure.reason))
+        .collect();
+    assert_eq!(
+        reasons,
+        vec![
+            ("a".to_string(), cycle_error(&["a", "b", "a"])),
+            ("b".to_string(), cycle_error(&["b", "a", "b"])),
+            (
+                "c".to_string(),
+                FailureReason::BlockedBy(vec!["b".to_string()])
+            ),
+        ]
+    );
+    assert_eq!(
+        reasons[0].1.to_string(),
+        "Circular reference: a -> b -> a blocks c"
+    );
+}
+
+#[test]
+fn test_failure_reasons_name_the_variable_the_evaluation_stopped_at() {
+    let expressions: BTreeMap<String, String> = [
+        ("x".to_string(), "1 / 0".to_string()),
+        ("y".to_string(), "x + zz".to_string()),
+        ("z".to_string(), "zz + x".to_string()),
+        ("w".to_string(), "zz + ww".to_string()),
+        ("chosen".to_string(), "1 ? price : x".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let context: BTreeMap<String, Decimal> = BTreeMap::new();
+    let resolved = std::cell::Cell::new(0);
+    let resolver = |_: &str| {
+        resolved.set(resolved.get() + 1);
+        Ok(None)
+    };
+    let failures =
+        evaluate_expressions(&expressions, &WithResolver::new(&context, resolver)).unwrap_err();
+    let reasons: BTreeMap<String, FailureReason> = failures
+        .into_iter()
+        .map(|failure| (failure.name, failure.reason))
+        .collect();
+    let names =
+        |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
+    assert_eq!(reasons["y"], FailureReason::BlockedBy(names(&["x"])));
+    assert_eq!(
+        reasons["z"],
+        FailureReason::MissingVariables(names(&["zz"]))
+    );
+    assert_eq!(
+        reasons["w"],
+        FailureReason::MissingVariables(names(&["zz"]))
+    );
+    // the branch that was not taken is not a reason, even though it failed
+    assert_eq!(
+        reasons["chosen"],
+        FailureReason::MissingVariables(names(&["price"]))
+    );
+    // zz is looked up once by z and once by w, and price once by chosen. y stops at x first.
+    assert_eq!(resolved.get(), 3);
+}
+
+#[test]
+fn test_evaluate_expressions_failure_reasons() {
+    let expressions: BTreeMap<String, String> = [
+        ("missing".to_string(), "a + x + y".to_string()),
+        ("syntax".to_string(), "a +".to_string()),
+        ("blocked".to_string(), "missing + syntax + a".to_string()),
+        ("fine".to_string(), "a * 2".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(1.))].iter().cloned().collect();
+    let failures = evaluate_expressions(&expressions, &context).unwrap_err();
+    let reasons: Vec<String> = failures.iter().map(|failure| failure.to_string()).collect();
+    assert_eq!(
+        reasons,
+        vec![
+            "blocked = missing + syntax + a: Blocked by failed expressions: missing",
+            "missing = a + x + y: Missing variables: x",
+            "syntax = a +: Unexpected token: + at position 2",
+        ]
+    );
+}
+
+#[test]
+fn test_evaluate_expressions_partial() {
+    let expressions: BTreeMap<String, String> = [
+        ("aplusb".to_string(), "a + b".to_string()),
+        ("ratio".to_string(), "a / c".to_string()),
+        ("scaled".to_string(), "ratio * aplusb".to_string()),
+        ("doubled".to_string(), "aplusb * 2".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let context: BTreeMap<String, Decimal> = [
+        ("a".to_string(), dec!(1.)),
+        ("b".to_string(), dec!(2.)),
+        ("c".to_string(), dec!(0.)),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let report = evaluate_expressions_partial(&expressions, &context);
+
+    assert!(!report.is_complete());
+    assert_eq!(report.results["aplusb"], dec!(3.));
+    assert_eq!(report.results["doubled"], dec!(6.));
+    assert_eq!(report.results["a"], dec!(1.));
+    // the context variables read by an expression that failed are included too
+    assert_eq!(report.results["c"], dec!(0.));
+    assert!(!report.results.contains_key("ratio"));
+    assert!(!report.results.contains_key("scaled"));
+    let failed: Vec<&str> = report
+        .failures
+        .iter()
+        .map(|failure| failure.name.as_str())
+        .collect();
+    assert_eq!(failed, vec!["ratio", "scaled"]);
+}
+
+#[test]
+fn test_evaluator_custom_functions() {
+    let mut evaluator = Evaluator::new();
+    evaluator.register_function("tier_rate", Arity::Fixed(1), |args| {
+        Ok(if args[0] > dec!(1000) {
+            dec!(0.2)
+        } else {
+            dec!(0.1)
+        })
+    });
+    evaluator.register_function("fx", Arity::Fixed(2), |args| match args[1] {
+        x if x == dec!(1) => Ok(args[0] * dec!(1.5)),
+        x => Err(format!("No rate for currency {}", x)),
+    });
+    evaluator.register_function("total", Arity::Variadic(0), |args| Ok(args.iter().sum()));
+
+    let context: BTreeMap<String, Decimal> = [("income".to_string(), dec!(2000.))]
+        .iter()
+        .cloned()
+        .collect();
+    let tests = vec![
+        ("income * tier_rate(income)", dec!(400.)),
+        ("tier_rate(500)", dec!(0.1)),
+        ("fx(max(10, 20), 1)", dec!(30.)),
+        ("total()", dec!(0.)),
+        ("total(1, 2, 3, tier_rate(1))", dec!(6.1)),
+    ];
+    for test in tests {
+        assert_eq!(
+            evaluator.evaluate_expression(test.0, &context).unwrap(),
+            test.1,
+            "Failed to evaluate: {}",
+            test.0
+        );
+    }
+
+    assert_eq!(
+        evaluator.evaluate_expression("fx(10, 2)", &context),
+        Err(ExpressurError::FunctionFailed {
+            token: "fx".to_string(),
+            position: 0,
+            message: "No rate for currency 2".to_string(),
+        })
+    );
+    assert_eq!(
+        evaluator.evaluate_expression("1 + fx(10)", &context),
+        Err(ExpressurError::WrongArgumentCount {
+            token: "fx".to_string(),
+            position: 4,
+            arguments: 1
+        })
+    );
+    assert_eq!(
+        evaluator.evaluate_expression("tier(10)", &context),
+        Err(ExpressurError::UnknownFunction {
+            token: "tier".to_string(),
+            position: 0
+        })
+    );
+    assert_eq!(
+        evaluate_expression("tier_rate(10)", &context),
+        Err(ExpressurError::UnknownFunction {
+            token: "tier_rate".to_string(),
+            position: 0
+        })
+    );
+}
+
+#[test]
+fn test_evaluator_custom_functions_in_expressions() {
+    let mut evaluator = Evaluator::new();
+    evaluator.register_function("double", Arity::Fixed(1), |args| Ok(args[0] * dec!(2)));
+    // replaces the built-in
+    evaluator.register_function("abs", Arity::Fixed(1), |_| Ok(dec!(42)));
+
+    let expressions: BTreeMap<String, String> = [
+        ("doubled".to_string(), "double(a)".to_string()),
+        ("quadrupled".to_string(), "double(doubled)".to_string()),
+        ("answer".to_string(), "abs(a)".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(3.))].iter().cloned().collect();
+    let results = evaluator
+        .evaluate_expressions(&expressions, &context)
+        .unwrap();
+    assert_eq!(results["quadrupled"], dec!(12.));
+    assert_eq!(results["answer"], dec!(42.));
+}
+
+#[test]
+fn test_logical_operators() {
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(4.)), ("b".to_string(), dec!(0.))]
+            .iter()
+            .cloned()
+            .collect();
+    let tests = vec![
+        ("1 and 1", dec!(1.)),
+        ("1 and 0", dec!(0.)),
+        ("0 or 2", dec!(1.)),
+        ("0 or 0", dec!(0.)),
+        ("not 0", dec!(1.)),
+        ("not 5", dec!(0.)),
+        ("!(a > 1)", dec!(0.)),
+        ("a > 1 && a < 5", dec!(1.)),
+        ("a < 1 || a = 4", dec!(1.)),
+        ("1 or 0 and 0", dec!(1.)),
+        ("not a = 4", dec!(0.)),
+        ("not not a", dec!(1.)),
+        ("not 1 or 1", dec!(1.)),
+        ("(1 or 0) + 1", dec!(2.)),
+        ("max(0 and 1, 1 or 0) * 3", dec!(3.)),
+        // the right operand is never evaluated
+        ("b != 0 and a / b > 1", dec!(0.)),
+        ("b = 0 or a / b > 1", dec!(1.)),
+        ("b and missing", dec!(0.)),
+        ("a or missing", dec!(1.)),
+    ];
+    for test in tests {
+        assert_eq!(
+            evaluate_expression(test.0, &context).unwrap(),
+            test.1,
+            "Failed to evaluate: {}",
+            test.0
+        );
+    }
+    assert_eq!(
+        evaluate_expression("a and missing", &context),
+        Err(ExpressurError::UnknownVariable {
+            token: "missing".to_string(),
+            position: 6
+        })
+    );
+    assert_eq!(
+        evaluate_expression("1 not 2", &context),
+        Err(ExpressurError::SyntaxError {
+            token: "not".to_string(),
+            position: 2
+        })
+    );
+}
+
+#[test]
+fn test_conditionals() {
+    let context: BTreeMap<String, Decimal> = [
+        ("sales".to_string(), dec!(1200.)),
+        ("target".to_string(), dec!(1000.)),
+        ("zero".to_string(), dec!(0.)),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let tests = vec![
+        ("if(sales > target, sales * 0.1, 0)", dec!(120.)),
+        ("if(sales < target, sales * 0.1, 0)", dec!(0.)),
+        ("sales > target ? sales * 0.1 : 0", dec!(120.)),
+        ("sales < target ? sales * 0.1 : -1", dec!(-1.)),
+        ("1 + (zero ? 2 : 3)", dec!(4.)),
+        ("zero ? 1 : zero ? 2 : 3", dec!(3.)),
+        ("1 ? 0 ? 1 : 2 : 3", dec!(2.)),
+        ("if(0, 1, if(1, 2, 3)) + if(1, 10, 20)", dec!(12.)),
+        ("max(1, zero ? 5 : 6, if(zero, 7, 0))", dec!(6.)),
+        ("0 or 1 ? 5 : 6", dec!(5.)),
+        // the branch that is not taken is never evaluated
+        ("if(zero = 0, 0, sales / zero)", dec!(0.)),
+        ("zero != 0 ? sales / zero : 1", dec!(1.)),
+        ("sales > target ? 1 : missing", dec!(1.)),
+    ];
+    for test in tests {
+        assert_eq!(
+            evaluate_expression(test.0, &context).unwrap(),
+            test.1,
+            "Failed to evaluate: {}",
+            test.0
+        );
+    }
+
+    let errors = vec![
+        (
+            "if(sales > target, missing, 0)",
+            ExpressurError::UnknownVariable {
+                token: "missing".to_string(),
+                position: 19,
+            },
+        ),
+        (
+            "if(1, 2)",
+            ExpressurError::WrongArgumentCount {
+                token: "if".to_string(),
+                position: 0,
+                arguments: 2,
+            },
+        ),
+        (
+            "1 ? 2",
+            ExpressurError::SyntaxError {
+                token: "?".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "1 : 2",
+            ExpressurError::SyntaxError {
+                token: ":".to_string(),
+                position: 2,
+            },
+        ),
+        (
+            "1 ? 2 : 3 : 4",
+            ExpressurError::SyntaxError {
+                token: ":".to_string(),
+                position: 10,
+            },
+        ),
+        (
+            "max(1 ? 2, 3)",
+            ExpressurError::SyntaxError {
+                token: "?".to_string(),
+                position: 6,
+            },
+        ),
+        (
+            "(1 ? 2) : 3",
+            ExpressurError::SyntaxError {
+                token: "?".to_string(),
+                position: 3,
+            },
+        ),
+        (
+            "? 1 : 2",
+            ExpressurError::SyntaxError {
+                token: "?".to_string(),
+                position: 0,
+            },
+        ),
+    ];
+    for (expression, error) in errors {
+        assert_eq!(
+            evaluate_expression(expression, &context),
+            Err(error),
+            "{}",
+            expression
+        );
+    }
+}
+
+#[test]
+fn test_power_precedence() {
+    let context = BTreeMap::new();
+    let tests = vec![
+        ("1 + 2 ^ 2", dec!(5.), dec!(9.)),
+        ("2 ^ 3 ^ 2", dec!(512.), dec!(64.)),
+        ("2 * 3 ^ 2", dec!(18.), dec!(36.)),
+        ("3 ^ 2 * 2", dec!(18.), dec!(81.)),
+        ("(2 ^ 3) ^ 2", dec!(64.), dec!(64.)),
+        ("2 ^ 2 - 1", dec!(3.), dec!(2.)),
+        ("-2 ^ 2", dec!(4.), dec!(4.)),
+        ("2 ^ 2 = 4", dec!(1.), dec!(1.)),
+    ];
+    let mut legacy = Evaluator::new();
+    legacy.set_power_precedence(PowerPrecedence::Legacy);
+    for (expression, standard_result, legacy_result) in tests {
+        assert_eq!(
+            evaluate_expression(expression, &context).unwrap(),
+            standard_result,
+            "Failed to evaluate: {}",
+            expression
+        );
+        assert_eq!(
+            legacy.evaluate_expression(expression, &context).unwrap(),
+            legacy_result,
+            "Failed to evaluate with legacy precedence: {}",
+            expression
+        );
+    }
+    assert_eq!(rpn_tokens("2 ^ 3 ^ 2"), vec!["2", "3", "2", "^", "^"]);
+    assert_eq!(rpn_tokens("1 - 2 - 3"), vec!["1", "2", "-", "3", "-"]);
+}
+
+#[test]
+fn test_prefix_signs() {
+    let context: BTreeMap<String, Decimal> = [
+        ("a".to_string(), dec!(3.)),
+        ("b".to_string(), dec!(4.)),
+        ("x".to_string(), dec!(-2.)),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let tests = vec![
+        ("-a", dec!(-3.)),
+        ("+a", dec!(3.)),
+        ("-(a + b)", dec!(-7.)),
+        ("2 * -x", dec!(4.)),
+        ("2*-x", dec!(4.)),
+        ("a - -x", dec!(1.)),
+        ("a--x", dec!(1.)),
+        ("- -a", dec!(3.)),
+        ("-a ^ 2", dec!(9.)),
+        ("2 ^ -x", dec!(4.)),
+        ("-max(a, b)", dec!(-4.)),
+        ("max(-a, -b)", dec!(-3.)),
+        ("-a < 0 and -x > 0", dec!(1.)),
+        ("b ? -a : +a", dec!(-3.)),
+        ("- 2", dec!(-2.)),
+    ];
+    for test in tests {
+        assert_eq!(
+            evaluate_expression(test.0, &context).unwrap(),
+            test.1,
+            "Failed to evaluate: {}",
+            test.0
+        );
+    }
+}
+
+#[test]
+fn test_evaluate_with_contexts() {
+    let expressions: BTreeMap<String, String> = [
+        ("total".to_string(), "price * quantity".to_string()),
+        ("price".to_string(), "base + 1".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+
+    let mut hash: std::collections::HashMap<String, Decimal> = std::collections::HashMap::new();
+    hash.insert("base".to_string(), dec!(4));
+    hash.insert("quantity".to_string(), dec!(3));
+    // an expression's result is used in place of a context variable with the same name
+    hash.insert("price".to_string(), dec!(100));
+    let results = evaluate_expressions(&expressions, &hash).unwrap();
+    assert_eq!(results["total"], dec!(15));
+    assert_eq!(results["price"], dec!(5));
+    // the context variables the expressions read are returned with the results
+    assert_eq!(results["quantity"], dec!(3));
+    assert_eq!(results.len(), 4);
+
+    let closure = |name: &str| match name {
+        "base" => Some(dec!(9)),
+        "quantity" => Some(dec!(2)),
+        _ => None,
+    };
+    assert_eq!(
+        evaluate_expressions(&expressions, &closure).unwrap()["total"],
+        dec!(20)
+    );
+    assert_eq!(
+        evaluate_expression("base * quantity", &closure).unwrap(),
+        dec!(18)
+    );
+
+    let overrides: BTreeMap<String, Decimal> = [("quantity".to_string(), dec!(10))]
+        .iter()
+        .cloned()
+        .collect();
+    let layered = Layered::new(&overrides, &closure);
+    assert_eq!(
+        evaluate_expressions(&expressions, &layered).unwrap()["total"],
+        dec!(100)
+    );
+
+    // a failed expression hides a context variable with the same name too
+    let expressions: BTreeMap<String, String> = [
+        ("total".to_string(), "price * quantity".to_string()),
+        ("price".to_string(), "missing + 1".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let failures = evaluate_expressions(&expressions, &hash).unwrap_err();
+    assert_eq!(
+        failures[1].reason,
+        FailureReason::BlockedBy(vec!["price".to_string()])
+    );
+}
+
+#[test]
+fn test_evaluate_expressions_with_resolver() {
+    let expressions: BTreeMap<String, String> = [
+        ("total".to_string(), "price * quantity".to_string()),
+        ("discounted".to_string(), "total - discount".to_string()),
+        ("risky".to_string(), "score + 1".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let context: BTreeMap<String, Decimal> = [("quantity".to_string(), dec!(3))]
+        .iter()
+        .cloned()
+        .collect();
+    let resolver = |name: &str| match name {
+        "price" => Ok(Some(dec!(5))),
+        "score" => Err("feature store timed out".to_string()),
+        _ => Ok(None),
+    };
+    let report = evaluate_expressions_partial(&expressions, &WithResolver::new(&context, resolver));
+
+    assert_eq!(report.results["total"], dec!(15));
+    assert_eq!(report.failures.len(), 2);
+    assert_eq!(report.failures[0].name, "discounted");
+    assert_eq!(
+        report.failures[0].reason,
+        FailureReason::MissingVariables(vec!["discount".to_string()])
+    );
+    assert_eq!(report.failures[1].name, "risky");
+    assert_eq!(
+        report.failures[1].reason,
+        FailureReason::Error(ExpressurError::ResolverFailed {
+            token: "score".to_string(),
+            position: 0,
+            message: "feature store timed out".to_string(),
+        })
+    );
+}
+
+#[test]
+fn test_evaluate_expressions_with_provenance() {
+    let expressions: BTreeMap<String, String> = [
+        ("aplusb".to_string(), "a + b".to_string()),
+        ("cplusaplusb".to_string(), "c + aplusb".to_string()),
+        ("guarded".to_string(), "a > 5 and b".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let context: BTreeMap<String, Decimal> = [
+        ("a".to_string(), dec!(1)),
+        ("b".to_string(), dec!(2)),
+        ("c".to_string(), dec!(4)),
+        ("unused".to_string(), dec!(9)),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    let values = |pairs: &[(&str, Decimal)]| -> BTreeMap<String, Decimal> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), *value))
+            .collect()
+    };
+
+    let (results, provenance) =
+        evaluate_expressions_with_provenance(&expressions, &context).unwrap();
+    assert_eq!(
+        results,
+        evaluate_expressions(&expressions, &context).unwrap()
+    );
+    assert_eq!(
+        provenance["cplusaplusb"],
+        Provenance {
+            formula: "c + aplusb".to_string(),
+            inputs: values(&[("aplusb", dec!(3)), ("c", dec!(4))]),
+            order: 1,
+        }
+    );
+    assert_eq!(provenance["aplusb"].order, 0);
+    // b is skipped by the "and", so it is not an input of the result
+    assert_eq!(provenance["guarded"].inputs, values(&[("a", dec!(1))]));
+
+    let json = serde_json::to_string(&provenance["aplusb"]).unwrap();
+    assert_eq!(
+        json,
+        r#"{"formula":"a + b","inputs":{"a":"1","b":"2"},"order":0}"#
+    );
+    assert_eq!(
+        serde_json::from_str::<Provenance>(&json).unwrap(),
+        provenance["aplusb"]
+    );
+
+    // provenance is only recorded when asked for
+    let mut evaluator = Evaluator::new();
+    assert_eq!(
+        evaluator
+            .evaluate_expressions_partial(&expressions, &context)
+            .provenance,
+        None
+    );
+    evaluator.set_record_provenance(true);
+    let mut broken = expressions.clone();
+    broken.insert("a_ratio".to_string(), "a / (b - 2)".to_string());
+    let report = evaluator.evaluate_expressions_partial(&broken, &context);
+    let provenance = report.provenance.unwrap();
+    assert_eq!(provenance.len(), 3);
+    assert!(!provenance.contains_key("a_ratio"));
+    // a_ratio is evaluated first and fails, so it does not take up a position
+    let orders: Vec<usize> = provenance.values().map(|record| record.order).collect();
+    assert_eq!(orders, vec![0, 1, 2]);
+    assert!(evaluate_expressions_with_provenance(&broken, &context).is_err());
+}
@@ -0,0 +1,538 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::error::{ExpressionFailure, ExpressurError};
+use crate::evaluator::Operator;
+use crate::prelude::*;
+use crate::tokenizer::{tokenize, Token};
+
+/// What a step of an evaluation applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// An operator, by its symbol, such as "+", "-" or "and". A prefix "-" or "+" has the same
+    /// symbol as the infix one, but a single operand.
+    Operator(String),
+    /// A function call, by the function's name.
+    Function(String),
+}
+
+impl Operation {
+    pub(crate) fn operator(operator: Operator) -> Operation {
+        Operation::Operator(operator.symbol().to_string())
+    }
+}
+
+/// A value an operation was applied to, and the variable it was read from, if it was not a
+/// number in the expression or the result of an earlier step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Operand {
+    pub value: Decimal,
+    pub source: Option<String>,
+}
+
+/// An operation applied while evaluating an expression, with the values it was applied to and
+/// its result.
+///
+/// A short-circuited "and" or "or" has a single operand, the one that decided its result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Step {
+    pub operation: Operation,
+    pub operands: Vec<Operand>,
+    pub result: Decimal,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values: Vec<String> = self
+            .operands
+            .iter()
+            .map(|operand| operand.value.to_string())
+            .collect();
+        match (&self.operation, values.as_slice()) {
+            (Operation::Function(name), _) => write!(f, "{}({})", name, values.join(", "))?,
+            (Operation::Operator(symbol), [x, y]) => write!(f, "{} {} {}", x, symbol, y)?,
+            (Operation::Operator(symbol), [x]) if symbol == "and" || symbol == "or" => {
+                write!(f, "{} {} ...", x, symbol)?
+            }
+            (Operation::Operator(symbol), [x]) if symbol == "not" => write!(f, "not {}", x)?,
+            (Operation::Operator(symbol), _) => write!(f, "{}{}", symbol, values.join(" "))?,
+        }
+        write!(f, " = {}", self.result)
+    }
+}
+
+/// How the value of an expression came about: the variables it read and the steps that were
+/// actually applied, in order. Branches that were not taken and operands that were skipped by a
+/// short-circuiting "and" or "or" have no steps.
+///
+/// It is displayed as the expression, the expression with its variables replaced by their values,
+/// and the value, such as `aplusb = a + b = 1 + 2 = 3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    /// The name of the expression, when it is one of a set.
+    pub name: Option<String>,
+    pub expression: String,
+    /// The values of the variables the evaluation read.
+    pub variables: BTreeMap<String, Decimal>,
+    pub steps: Vec<Step>,
+    pub value: Decimal,
+}
+
+impl Explanation {
+    /// The expression with the variables it read replaced by their values.
+    pub fn substituted(&self) -> String {
+        let tokens = match tokenize(&self.expression) {
+            Ok(tokens) => tokens,
+            Err(_) => return self.expression.trim().to_string(),
+        };
+        let mut substituted = String::new();
+        let mut end = 0;
+        for token in tokens {
+            let span = token.span();
+            substituted.push_str(&self.expression[end..span.start]);
+            match (&token, self.variables.get(token.text(&self.expression))) {
+                (Token::Identifier(_), Some(value)) => substituted.push_str(&value.to_string()),
+                _ => substituted.push_str(token.text(&self.expression)),
+            }
+            end = span.end;
+        }
+        substituted.trim().to_string()
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{} = ", name)?;
+        }
+        let expression = self.expression.trim();
+        let substituted = self.substituted();
+        write!(f, "{}", expression)?;
+        if substituted != expression {
+            write!(f, " = {}", substituted)?;
+        }
+        // "total = 5" rather than "total = 5 = 5"
+        if substituted != self.value.to_string() {
+            write!(f, " = {}", self.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The explanations of a set of expressions, in the order they were evaluated, so every
+/// expression comes after the expressions it references.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExplanationSet {
+    explanations: Vec<Explanation>,
+}
+
+impl ExplanationSet {
+    /// The explanation of the named expression.
+    pub fn get(&self, name: &str) -> Option<&Explanation> {
+        self.explanations
+            .iter()
+            .find(|explanation| explanation.name.as_deref() == Some(name))
+    }
+
+    /// All the explanations, in evaluation order.
+    pub fn iter(&self) -> impl Iterator<Item = &Explanation> {
+        self.explanations.iter()
+    }
+
+    /// The explanation of the named expression and of every expression it depends on, directly or
+    /// through other expressions, in evaluation order. Empty if there is no such expression.
+    pub fn chain(&self, name: &str) -> Vec<&Explanation> {
+        let mut needed: BTreeSet<&str> = BTreeSet::new();
+        let mut pending = vec![name];
+        while let Some(name) = pending.pop() {
+            if let Some(explanation) = self.get(name) {
+                if needed.insert(name) {
+                    pending.extend(explanation.variables.keys().map(String::as_str));
+                }
+            }
+        }
+        self.explanations
+            .iter()
+            .filter(|explanation| needed.contains(explanation.name.as_deref().unwrap_or_default()))
+            .collect()
+    }
+
+    /// The chain of the named expression, one explanation per line, or None if there is no such
+    /// expression.
+    pub fn render(&self, name: &str) -> Option<String> {
+        let lines: Vec<String> = self
+            .chain(name)
+            .iter()
+            .map(|explanation| explanation.to_string())
+            .collect();
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+/// Records what an evaluation does, alongside its stack.
+#[derive(Debug, Default)]
+pub(crate) struct Trace {
+    steps: Vec<Step>,
+    variables: BTreeMap<String, Decimal>,
+    /// The variable each value on the stack was read from, if any.
+    sources: Vec<Option<String>>,
+    /// The left operands of the "and"s and "or"s whose right operands are being evaluated.
+    deferred: Vec<(Operation, Operand)>,
+    /// Only the variables are recorded, not the steps.
+    variables_only: bool,
+}
+
+impl Trace {
+    /// A trace that only records the variables the evaluation read, for callers that have no use
+    /// for the steps.
+    pub(crate) fn variables_only() -> Trace {
+        Trace {
+            variables_only: true,
+            ..Trace::default()
+        }
+    }
+
+    /// The values of the variables the evaluation read.
+    pub(crate) fn into_variables(self) -> BTreeMap<String, Decimal> {
+        self.variables
+    }
+
+    /// The explanation of the evaluation that was traced, which produced the value.
+    pub(crate) fn into_explanation(
+        self,
+        name: Option<&str>,
+        expression: &str,
+        value: Decimal,
+    ) -> Explanation {
+        Explanation {
+            name: name.map(String::from),
+            expression: expression.to_string(),
+            variables: self.variables,
+            steps: self.steps,
+            value,
+        }
+    }
+
+    /// A number was pushed.
+    pub(crate) fn push(&mut self, source: Option<String>) {
+        if self.variables_only {
+            return;
+        }
+        self.sources.push(source);
+    }
+
+    /// A variable's value was pushed.
+    pub(crate) fn load(&mut self, name: &str, value: Decimal) {
+        self.variables.insert(name.to_string(), value);
+        self.push(Some(name.to_string()));
+    }
+
+    /// The operands were popped and replaced by the result of the operation.
+    pub(crate) fn apply(&mut self, operation: Operation, operands: &[Decimal], result: Decimal) {
+        if self.variables_only {
+            return;
+        }
+        let sources = self
+            .sources
+            .split_off(self.sources.len().saturating_sub(operands.len()));
+        let operands = operands
+            .iter()
+            .zip(sources.into_iter().chain(std::iter::repeat(None)))
+            .map(|(value, source)| Operand {
+                value: *value,
+                source,
+            })
+            .collect();
+        self.steps.push(Step {
+            operation,
+            operands,
+            result,
+        });
+        self.push(None);
+    }
+
+    /// The left operand of an "and" or "or" was popped, and the right operand decides the result.
+    pub(crate) fn defer(&mut self, operator: Operator, value: Decimal) {
+        if self.variables_only {
+            return;
+        }
+        let source = self.sources.pop().flatten();
+        self.deferred
+            .push((Operation::operator(operator), Operand { value, source }));
+    }
+
+    /// The right operand of the innermost deferred "and" or "or" was turned into its result.
+    pub(crate) fn resume(&mut self, value: Decimal, result: Decimal) {
+        if self.variables_only {
+            return;
+        }
+        let source = self.sources.pop().flatten();
+        if let Some((operation, left)) = self.deferred.pop() {
+            self.steps.push(Step {
+                operation,
+                operands: vec![left, Operand { value, source }],
+                result,
+            });
+        }
+        self.push(None);
+    }
+
+    /// A value was popped without being used in a step, such as the condition of a conditional.
+    pub(crate) fn discard(&mut self) {
+        if self.variables_only {
+            return;
+        }
+        self.sources.pop();
+    }
+}
+
+/// Evaluates a compiled expression, recording how its value came about.
+pub(crate) fn explain_compiled(
+    compiled: &CompiledExpression,
+    name: Option<&str>,
+    expression: &str,
+    context: &impl Context,
+) -> Result<Explanation, ExpressurError> {
+    let mut trace = Trace::default();
+    let value = compiled.run(context, Some(&mut trace))?;
+    Ok(trace.into_explanation(name, expression, value))
+}
+
+pub(crate) fn explanation_set(explanations: Vec<Explanation>) -> ExplanationSet {
+    ExplanationSet { explanations }
+}
+
+/// Evaluates an arithmetic expression like `evaluate_expression`, and explains how its value came
+/// about: the variables it read, and each operation that was applied with its operands and result.
+///
+/// # Errors
+/// The same errors as `evaluate_expression`.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+/// let mut context = BTreeMap::new();
+/// context.insert("a".to_string(), dec!(1));
+/// context.insert("b".to_string(), dec!(2));
+///
+/// let explanation = explain_expression("a + b * 2", &context).unwrap();
+/// assert_eq!(explanation.value, dec!(5));
+/// assert_eq!(explanation.to_string(), "a + b * 2 = 1 + 2 * 2 = 5");
+/// let steps: Vec<String> = explanation.steps.iter().map(|step| step.to_string()).collect();
+/// assert_eq!(steps, vec!["2 * 2 = 4", "1 + 4 = 5"]);
+/// assert_eq!(explanation.steps[0].operands[0].source, Some("b".to_string()));
+/// ```
+pub fn explain_expression(
+    expression: &str,
+    context: &impl Context,
+) -> Result<Explanation, ExpressurError> {
+    Evaluator::new().explain_expression(expression, context)
+}
+
+/// Evaluates a list of arithmetic expressions like `evaluate_expressions`, and explains each of
+/// them. The explanation of an expression can be followed through the expressions it references
+/// with `ExplanationSet::chain`.
+///
+/// # Errors
+/// The same failures as `evaluate_expressions`.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+/// use std::collections::BTreeMap;
+/// let mut expressions: BTreeMap<String, String> = BTreeMap::new();
+/// expressions.insert("aplusb".to_string(), "a + b".to_string());
+/// expressions.insert("doubled".to_string(), "aplusb * 2".to_string());
+///
+/// let mut context = BTreeMap::new();
+/// context.insert("a".to_string(), dec!(1));
+/// context.insert("b".to_string(), dec!(2));
+///
+/// let explanations = explain_expressions(&expressions, &context).unwrap();
+/// assert_eq!(
+///     explanations.render("doubled").unwrap(),
+///     "aplusb = a + b = 1 + 2 = 3\ndoubled = aplusb * 2 = 3 * 2 = 6"
+/// );
+/// ```
+pub fn explain_expressions(
+    expressions: &BTreeMap<String, String>,
+    context: &impl Context,
+) -> Result<ExplanationSet, Vec<ExpressionFailure>> {
+    Evaluator::new().explain_expressions(expressions, context)
+}
+
+#[test]
+fn test_explain_steps() {
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(1)), ("b".to_string(), dec!(0))]
+            .iter()
+            .cloned()
+            .collect();
+    let tests = vec![
+        (
+            "a + 2 * 3",
+            vec!["2 * 3 = 6", "1 + 6 = 7"],
+            "a + 2 * 3 = 1 + 2 * 3 = 7",
+        ),
+        (
+            "-a ^ 2",
+            vec!["-1 = -1", "-1 ^ 2 = 1"],
+            "-a ^ 2 = -1 ^ 2 = 1",
+        ),
+        ("not b", vec!["not 0 = 1.0"], "not b = not 0 = 1.0"),
+        (
+            "max(a, 5, b)",
+            vec!["max(1, 5, 0) = 5"],
+            "max(a, 5, b) = max(1, 5, 0) = 5",
+        ),
+        (
+            "b and a",
+            vec!["0 and ... = 0.0"],
+            "b and a = 0 and a = 0.0",
+        ),
+        ("a and b", vec!["1 and 0 = 0.0"], "a and b = 1 and 0 = 0.0"),
+        (
+            "a > 0 ? a * 10 : b",
+            vec!["1 > 0 = 1.0", "1 * 10 = 10"],
+            "a > 0 ? a * 10 : b = 1 > 0 ? 1 * 10 : b = 10",
+        ),
+        ("if(b, a, 4)", vec![], "if(b, a, 4) = if(0, a, 4) = 4"),
+        ("  7  ", vec![], "7"),
+    ];
+    for (expression, steps, rendered) in tests {
+        let explanation = explain_expression(expression, &context).unwrap();
+        let actual: Vec<String> = explanation
+            .steps
+            .iter()
+            .map(|step| step.to_string())
+            .collect();
+        assert_eq!(actual, steps, "{}", expression);
+        assert_eq!(explanation.to_string(), rendered, "{}", expression);
+    }
+
+    let explanation = explain_expression("a - a * 2", &context).unwrap();
+    assert_eq!(
+        explanation.variables,
+        [("a".to_string(), dec!(1))].iter().cloned().collect()
+    );
+    assert_eq!(
+        explanation.steps[1].operands,
+        vec![
+            Operand {
+                value: dec!(1),
+                source: Some("a".to_string())
+            },
+            Operand {
+                value: dec!(2),
+                source: None
+            },
+        ]
+    );
+    assert_eq!(
+        explain_expression("a / b", &context),
+        Err(ExpressurError::DivisionByZero {
+            token: "/".to_string(),
+            position: 2
+        })
+    );
+}
+
+#[test]
+fn test_trace_variables_only() {
+    let context: BTreeMap<String, Decimal> =
+        [("a".to_string(), dec!(1)), ("b".to_string(), dec!(0))]
+            .iter()
+            .cloned()
+            .collect();
+    let compiled = CompiledExpression::compile("(b or a) + -a * 2").unwrap();
+    let mut trace = Trace::variables_only();
+    assert_eq!(compiled.run(&context, Some(&mut trace)), Ok(dec!(-1)));
+    assert!(trace.steps.is_empty());
+    assert!(trace.sources.is_empty());
+    assert_eq!(trace.into_variables(), context);
+}
+
+#[test]
+fn test_explain_expression_chains() {
+    let mut expressions: BTreeMap<String, String> = BTreeMap::new();
+    expressions.insert("aplusb".to_string(), "a + b".to_string());
+    expressions.insert("cplusaplusb".to_string(), "c + aplusb".to_string());
+    expressions.insert("ratio".to_string(), "aplusb / cplusaplusb".to_string());
+    expressions.insert("other".to_string(), "c * 2".to_string());
+    let context: BTreeMap<String, Decimal> = [
+        ("a".to_string(), dec!(1)),
+        ("b".to_string(), dec!(2)),
+        ("c".to_string(), dec!(3)),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+
+    let explanations = explain_expressions(&expressions, &context).unwrap();
+    assert_eq!(explanations.iter().count(), 4);
+    let chain: Vec<&str> = explanations
+        .chain("ratio")
+        .iter()
+        .filter_map(|explanation| explanation.name.as_deref())
+        .collect();
+    assert_eq!(chain, vec!["aplusb", "cplusaplusb", "ratio"]);
+    assert_eq!(
+        explanations.render("ratio").unwrap(),
+        "aplusb = a + b = 1 + 2 = 3\n\
+         cplusaplusb = c + aplusb = 3 + 3 = 6\n\
+         ratio = aplusb / cplusaplusb = 3 / 6 = 0.50"
+    );
+    assert_eq!(
+        explanations.render("other").unwrap(),
+        "other = c * 2 = 3 * 2 = 6"
+    );
+    assert_eq!(explanations.render("a"), None);
+
+    expressions.insert("broken".to_string(), "d + 1".to_string());
+    let failures = explain_expressions(&expressions, &context).unwrap_err();
+    assert_eq!(failures.len(), 1);
+    assert_eq!(failures[0].name, "broken");
+}
+
+#[test]
+fn test_explain_expressions_evaluates_once() {
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let mut expressions: BTreeMap<String, String> = BTreeMap::new();
+    expressions.insert("total".to_string(), "price * 2".to_string());
+    expressions.insert("taxed".to_string(), "tax(total)".to_string());
+    let context: BTreeMap<String, Decimal> = BTreeMap::new();
+    let resolved = Cell::new(0);
+    let resolver = |name: &str| {
+        resolved.set(resolved.get() + 1);
+        Ok((name == "price").then_some(dec!(5)))
+    };
+    let called = Arc::new(AtomicUsize::new(0));
+    let mut evaluator = Evaluator::new();
+    let counter = called.clone();
+    evaluator.register_function("tax", Arity::Fixed(1), move |args| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Ok(args[0] * dec!(1.5))
+    });
+
+    let explanations = evaluator
+        .explain_expressions(&expressions, &WithResolver::new(&context, resolver))
+        .unwrap();
+    assert_eq!(
+        explanations.render("taxed").unwrap(),
+        "total = price * 2 = 5 * 2 = 10\ntaxed = tax(total) = tax(10) = 15.0"
+    );
+    assert_eq!(resolved.get(), 1);
+    assert_eq!(called.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,237 @@
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::{MathematicalOps, RoundingStrategy};
+
+use std::sync::Arc;
+
+use crate::error::ExpressurError;
+use crate::prelude::*;
+
+/// How many arguments a custom function accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    /// Exactly this many arguments.
+    Fixed(usize),
+    /// At least this many arguments.
+    Variadic(usize),
+}
+
+impl Arity {
+    /// The smallest and largest number of arguments.
+    fn bounds(&self) -> (usize, usize) {
+        match self {
+            Arity::Fixed(arguments) => (*arguments, *arguments),
+            Arity::Variadic(arguments) => (*arguments, usize::MAX),
+        }
+    }
+}
+
+/// A Rust function registered with an Evaluator so expressions can call it by name.
+pub(crate) struct CustomFunction {
+    pub(crate) name: String,
+    pub(crate) arity: Arity,
+    #[allow(clippy::type_complexity)]
+    pub(crate) function: Box<dyn Fn(&[Decimal]) -> Result<Decimal, String> + Send + Sync>,
+}
+
+impl std::fmt::Debug for CustomFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CustomFunction")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+/// The functions that can be called from an expression: the built-ins, and the custom functions
+/// registered with an Evaluator.
+#[derive(Debug, Clone)]
+pub(crate) enum Function {
+    Min,
+    Max,
+    Abs,
+    Round,
+    Floor,
+    Ceil,
+    Sqrt,
+    Ln,
+    Exp,
+    Custom(Arc<CustomFunction>),
+}
+
+impl PartialEq for Function {
+    fn eq(&self, other: &Function) -> bool {
+        match (self, other) {
+            (Function::Custom(x), Function::Custom(y)) => Arc::ptr_eq(x, y),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Function {
+    pub(crate) fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "abs" => Some(Function::Abs),
+            "round" => Some(Function::Round),
+            "floor" => Some(Function::Floor),
+            "ceil" => Some(Function::Ceil),
+            "sqrt" => Some(Function::Sqrt),
+            "ln" => Some(Function::Ln),
+            "exp" => Some(Function::Exp),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Abs => "abs",
+            Function::Round => "round",
+            Function::Floor => "floor",
+            Function::Ceil => "ceil",
+            Function::Sqrt => "sqrt",
+            Function::Ln => "ln",
+            Function::Exp => "exp",
+            Function::Custom(custom) => &custom.name,
+        }
+    }
+
+    /// The smallest and largest number of arguments the function accepts.
+    pub(crate) fn arity(&self) -> (usize, usize) {
+        match self {
+            Function::Min | Function::Max => (1, usize::MAX),
+            Function::Round => (1, 2),
+            Function::Custom(custom) => custom.arity.bounds(),
+            _ => (1, 1),
+        }
+    }
+
+    /// Applies the function to its arguments, which the compiler has already checked against
+    /// the function's arity.
+    pub(crate) fn apply(
+        &self,
+        args: &[Decimal],
+        position: usize,
+    ) -> Result<Decimal, ExpressurError> {
+        let invalid_argument = || ExpressurError::InvalidArgument {
+            token: self.name().to_string(),
+            position,
+        };
+        let x = args.first().copied().unwrap_or_default();
+        match self {
+            Function::Min => Ok(args.iter().copied().fold(x, Decimal::min)),
+            Function::Max => Ok(args.iter().copied().fold(x, Decimal::max)),
+            Function::Abs => Ok(x.abs()),
+            Function::Round => {
+                // the number of decimal places, which has to be a whole number from 0 to 28
+                let digits = match args.get(1) {
+                    None => 0,
+                    Some(digits) if digits.fract().is_zero() => digits
+                        .to_u32()
+                        .filter(|digits| *digits <= 28)
+                        .ok_or_else(invalid_argument)?,
+                    Some(_) => return Err(invalid_argument()),
+                };
+                Ok(x.round_dp_with_strategy(digits, RoundingStrategy::MidpointAwayFromZero))
+            }
+            Function::Floor => Ok(x.floor()),
+            Function::Ceil => Ok(x.ceil()),
+            Function::Sqrt => x.sqrt().ok_or_else(invalid_argument),
+            Function::Ln => x.checked_ln().ok_or_else(invalid_argument),
+            Function::Exp => checked_power(Decimal::E, x).ok_or(ExpressurError::Overflow {
+                token: self.name().to_string(),
+                position,
+            }),
+            Function::Custom(custom) => {
+                (custom.function)(args).map_err(|message| ExpressurError::FunctionFailed {
+                    token: custom.name.to_string(),
+                    position,
+                    message,
+                })
+            }
+        }
+    }
+}
+
+/// x raised to the power y, or None if the result is too large for a Decimal. A result too close
+/// to zero for a Decimal rounds to 0, as a quotient does.
+pub(crate) fn checked_power(x: Decimal, y: Decimal) -> Option<Decimal> {
+    x.checked_powd(y).or_else(|| {
+        // the result is smaller than 1 when x is larger than 1 and y negative, or the reverse
+        let shrinks = (x.abs() > Decimal::ONE) == (y < Decimal::ZERO);
+        shrinks.then_some(Decimal::ZERO)
+    })
+}
+
+#[test]
+fn test_apply_functions() {
+    let tests = vec![
+        (Function::Min, vec![dec!(3), dec!(-1), dec!(2)], dec!(-1)),
+        (Function::Max, vec![dec!(3), dec!(-1), dec!(2)], dec!(3)),
+        (Function::Max, vec![dec!(7)], dec!(7)),
+        (Function::Abs, vec![dec!(-2.5)], dec!(2.5)),
+        (Function::Round, vec![dec!(2.5)], dec!(3)),
+        (Function::Round, vec![dec!(-2.5)], dec!(-3)),
+        (Function::Round, vec![dec!(1.23456), dec!(2)], dec!(1.23)),
+        (Function::Round, vec![dec!(1.235), dec!(2.0)], dec!(1.24)),
+        (Function::Floor, vec![dec!(-1.5)], dec!(-2)),
+        (Function::Ceil, vec![dec!(1.2)], dec!(2)),
+        (Function::Sqrt, vec![dec!(16)], dec!(4)),
+        (Function::Ln, vec![dec!(1)], dec!(0)),
+        (Function::Exp, vec![dec!(0)], dec!(1)),
+    ];
+    for test in tests {
+        assert_eq!(
+            test.0.apply(&test.1, 0).unwrap(),
+            test.2,
+            "Failed to apply: {:?}",
+            test.0
+        );
+    }
+}
+
+#[test]
+fn test_apply_functions_invalid_arguments() {
+    let invalid = |name: &str| ExpressurError::InvalidArgument {
+        token: name.to_string(),
+        position: 4,
+    };
+    assert_eq!(Function::Sqrt.apply(&[dec!(-1)], 4), Err(invalid("sqrt")));
+    assert_eq!(Function::Ln.apply(&[dec!(0)], 4), Err(invalid("ln")));
+    assert_eq!(
+        Function::Round.apply(&[dec!(1), dec!(0.5)], 4),
+        Err(invalid("round"))
+    );
+    assert_eq!(
+        Function::Round.apply(&[dec!(1), dec!(-1)], 4),
+        Err(invalid("round"))
+    );
+}
+
+#[test]
+fn test_underflow_rounds_to_zero() {
+    assert_eq!(checked_power(dec!(2), dec!(-95)), Some(dec!(0)));
+    assert_eq!(checked_power(dec!(2), dec!(-100)), Some(dec!(0)));
+    assert_eq!(checked_power(dec!(0.5), dec!(300)), Some(dec!(0)));
+    assert_eq!(checked_power(dec!(-2), dec!(-101)), Some(dec!(0)));
+    assert_eq!(checked_power(dec!(2), dec!(100)), None);
+    assert_eq!(checked_power(dec!(0.5), dec!(-100)), None);
+    let context = BTreeMap::new();
+    assert_eq!(evaluate_expression("2 ^ -100", &context), Ok(dec!(0)));
+
+    assert_eq!(Function::Exp.apply(&[dec!(-70)], 0), Ok(dec!(0)));
+    assert_eq!(Function::Exp.apply(&[dec!(-1000)], 0), Ok(dec!(0)));
+    assert_eq!(
+        Function::Exp.apply(&[dec!(12)], 0).unwrap().round_dp(2),
+        dec!(162754.79)
+    );
+    assert_eq!(
+        Function::Exp.apply(&[dec!(100)], 4),
+        Err(ExpressurError::Overflow {
+            token: "exp".to_string(),
+            position: 4
+        })
+    );
+}
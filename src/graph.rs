@@ -0,0 +1,1035 @@
+use std::borrow::Borrow;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
+
+use rust_decimal::Decimal;
+
+use crate::analysis::referenced_variables;
+use crate::compiled::CompiledExpression;
+use crate::error::{ExpressionFailure, ExpressurError, FailureReason};
+
+/// The dependencies between a set of named expressions, and the inputs they read.
+///
+/// An expression depends on another when it references the other's name. Every other variable
+/// an expression references is an input, such as a context variable.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use std::collections::BTreeMap;
+///
+/// let expressions: BTreeMap<String, String> = [
+///     ("aplusb", "a + b"),
+///     ("cplusaplusb", "c + aplusb"),
+///     ("extraindirection", "(aplusb/ cplusaplusb)"),
+/// ]
+/// .iter()
+/// .map(|(name, expression)| (name.to_string(), expression.to_string()))
+/// .collect();
+/// let graph = DependencyGraph::build(&expressions).unwrap();
+///
+/// // which expressions change if c changes?
+/// assert_eq!(graph.downstream("c"), ["cplusaplusb", "extraindirection"].into());
+/// // which inputs does extraindirection ultimately depend on?
+/// assert_eq!(graph.upstream_inputs("extraindirection"), ["a", "b", "c"].into());
+/// assert_eq!(graph.layers(), vec![vec!["aplusb"], vec!["cplusaplusb"], vec!["extraindirection"]]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyGraph {
+    /// For each expression, the expressions it references directly.
+    dependencies: BTreeMap<String, BTreeSet<String>>,
+    /// For each expression, the expressions that reference it directly.
+    dependents: BTreeMap<String, BTreeSet<String>>,
+    /// For each expression, the inputs it references directly.
+    inputs: BTreeMap<String, BTreeSet<String>>,
+    /// For each input, the expressions that reference it directly.
+    readers: BTreeMap<String, BTreeSet<String>>,
+}
+
+/// Orders a set of compiled expressions like `DependencyGraph::evaluation_order`, without building
+/// the graph. The expressions are numbered in order of their names and only their references to
+/// each other are kept, so no names are copied, and when each of them only references expressions
+/// named before it they are simply returned in order.
+pub(crate) fn evaluation_order<K: Borrow<str> + Ord>(
+    expressions: &BTreeMap<K, CompiledExpression>,
+) -> (Vec<&str>, Vec<&str>) {
+    let names: Vec<&str> = expressions.keys().map(|name| name.borrow()).collect();
+    let in_order = expressions.values().enumerate().all(|(index, expression)| {
+        expression.variables().iter().all(|variable| {
+            names
+                .binary_search(&variable.as_str())
+                .map_or(true, |reference| reference < index)
+        })
+    });
+    if in_order {
+        return (names, Vec::new());
+    }
+
+    // the expressions each expression references, by number
+    let dependencies: Vec<Vec<usize>> = expressions
+        .values()
+        .map(|expression| {
+            expression
+                .variables()
+                .iter()
+                .filter_map(|variable| names.binary_search(&variable.as_str()).ok())
+                .collect()
+        })
+        .collect();
+    let mut unresolved: Vec<usize> = dependencies
+        .iter()
+        .map(|references| references.len())
+        .collect();
+    let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); names.len()];
+    for (index, references) in dependencies.iter().enumerate() {
+        for reference in references {
+            dependents[*reference].push(index);
+        }
+    }
+    // the expressions whose dependencies have all been evaluated, first by name
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..names.len())
+        .filter(|index| unresolved[*index] == 0)
+        .map(Reverse)
+        .collect();
+    let mut order: Vec<&str> = Vec::with_capacity(names.len());
+    while let Some(Reverse(index)) = ready.pop() {
+        order.push(names[index]);
+        for dependent in &dependents[index] {
+            unresolved[*dependent] -= 1;
+            if unresolved[*dependent] == 0 {
+                ready.push(Reverse(*dependent));
+            }
+        }
+    }
+
+    let blocked = (0..names.len())
+        .filter(|index| unresolved[*index] > 0)
+        .map(|index| names[index])
+        .collect();
+    (order, blocked)
+}
+
+/// How a node of a DependencyGraph is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    Input,
+    Intermediate,
+    Output,
+}
+
+impl DependencyGraph {
+    pub(crate) fn new<K: Borrow<str>>(
+        expressions: &BTreeMap<K, CompiledExpression>,
+    ) -> DependencyGraph {
+        DependencyGraph::from_variables(
+            expressions
+                .iter()
+                .map(|(name, expression)| {
+                    (
+                        name.borrow().to_string(),
+                        expression.variables().iter().cloned().collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Builds the graph of a set of expressions like the one passed to `evaluate_expressions`.
+    /// The expressions are only tokenized, so they may call functions that are not registered.
+    ///
+    /// # Errors
+    /// The first expression, by name, that cannot be tokenized.
+    pub fn build(
+        expressions: &BTreeMap<String, String>,
+    ) -> Result<DependencyGraph, ExpressionFailure> {
+        let mut variables: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for (name, expression) in expressions {
+            let references =
+                referenced_variables(expression).map_err(|error| ExpressionFailure {
+                    name: name.to_string(),
+                    expression: expression.to_string(),
+                    reason: FailureReason::Error(error),
+                })?;
+            variables.insert(name.to_string(), references.variables);
+        }
+        Ok(DependencyGraph::from_variables(variables))
+    }
+
+    /// Builds the graph from the variables each expression references.
+    fn from_variables(variables: BTreeMap<String, BTreeSet<String>>) -> DependencyGraph {
+        let mut dependencies: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut dependents: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut inputs: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut readers: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for name in variables.keys() {
+            dependencies.insert(name.to_string(), BTreeSet::new());
+            dependents.insert(name.to_string(), BTreeSet::new());
+            inputs.insert(name.to_string(), BTreeSet::new());
+        }
+        for (name, references) in &variables {
+            for variable in references {
+                if variables.contains_key(variable) {
+                    dependencies
+                        .get_mut(name)
+                        .unwrap()
+                        .insert(variable.to_string());
+                    dependents
+                        .get_mut(variable)
+                        .unwrap()
+                        .insert(name.to_string());
+                } else {
+                    inputs.get_mut(name).unwrap().insert(variable.to_string());
+                    readers
+                        .entry(variable.to_string())
+                        .or_default()
+                        .insert(name.to_string());
+                }
+            }
+        }
+        DependencyGraph {
+            dependencies,
+            dependents,
+            inputs,
+            readers,
+        }
+    }
+
+    /// Adds an expression, or replaces the variables an existing one references, only updating
+    /// the references to and from that expression. Expressions that read an input with the same
+    /// name now depend on the expression instead.
+    pub(crate) fn insert(&mut self, name: &str, variables: &[String]) {
+        if self.dependencies.contains_key(name) {
+            self.remove_references(name);
+        } else {
+            let readers = self.readers.remove(name).unwrap_or_default();
+            for reader in &readers {
+                self.inputs.get_mut(reader).unwrap().remove(name);
+                self.dependencies
+                    .get_mut(reader)
+                    .unwrap()
+                    .insert(name.to_string());
+            }
+            self.dependencies.insert(name.to_string(), BTreeSet::new());
+            self.dependents.insert(name.to_string(), readers);
+            self.inputs.insert(name.to_string(), BTreeSet::new());
+        }
+        for variable in variables {
+            if self.dependencies.contains_key(variable) {
+                self.dependencies
+                    .get_mut(name)
+                    .unwrap()
+                    .insert(variable.to_string());
+                self.dependents
+                    .get_mut(variable)
+                    .unwrap()
+                    .insert(name.to_string());
+            } else {
+                self.inputs
+                    .get_mut(name)
+                    .unwrap()
+                    .insert(variable.to_string());
+                self.readers
+                    .entry(variable.to_string())
+                    .or_default()
+                    .insert(name.to_string());
+            }
+        }
+    }
+
+    /// Removes an expression. The expressions that referenced it read an input with the same
+    /// name instead.
+    pub(crate) fn remove(&mut self, name: &str) {
+        if !self.dependencies.contains_key(name) {
+            return;
+        }
+        self.remove_references(name);
+        self.dependencies.remove(name);
+        self.inputs.remove(name);
+        let dependents = self.dependents.remove(name).unwrap_or_default();
+        for dependent in &dependents {
+            self.dependencies.get_mut(dependent).unwrap().remove(name);
+            self.inputs
+                .get_mut(dependent)
+                .unwrap()
+                .insert(name.to_string());
+        }
+        if !dependents.is_empty() {
+            self.readers.insert(name.to_string(), dependents);
+        }
+    }
+
+    /// Removes the references from an expression to the expressions and inputs it reads.
+    fn remove_references(&mut self, name: &str) {
+        for dependency in std::mem::take(self.dependencies.get_mut(name).unwrap()) {
+            self.dependents.get_mut(&dependency).unwrap().remove(name);
+        }
+        for input in std::mem::take(self.inputs.get_mut(name).unwrap()) {
+            let readers = self.readers.get_mut(&input).unwrap();
+            readers.remove(name);
+            if readers.is_empty() {
+                self.readers.remove(&input);
+            }
+        }
+    }
+
+    /// The names of the expressions, in order.
+    pub fn expressions(&self) -> impl Iterator<Item = &str> {
+        self.dependencies.keys().map(|name| name.as_str())
+    }
+
+    /// The variables the expressions reference that are not expressions themselves, in order.
+    pub fn inputs(&self) -> impl Iterator<Item = &str> {
+        self.readers.keys().map(|name| name.as_str())
+    }
+
+    /// The expressions that no other expression references, in order.
+    pub fn outputs(&self) -> impl Iterator<Item = &str> {
+        self.dependents
+            .iter()
+            .filter(|(_, dependents)| dependents.is_empty())
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// True if `name` is referenced by an expression without being one.
+    pub fn is_input(&self, name: &str) -> bool {
+        self.readers.contains_key(name)
+    }
+
+    /// Everything `name` depends on, directly or indirectly: both expressions and inputs. Empty
+    /// for an input, or a name that is not in the graph.
+    pub fn upstream(&self, name: &str) -> BTreeSet<&str> {
+        let mut upstream: BTreeSet<&str> = BTreeSet::new();
+        let mut to_visit: Vec<&str> = vec![name];
+        while let Some(next) = to_visit.pop() {
+            if let Some(dependencies) = self.dependencies.get(next) {
+                for dependency in dependencies {
+                    if upstream.insert(dependency) {
+                        to_visit.push(dependency);
+                    }
+                }
+                upstream.extend(self.inputs[next].iter().map(|input| input.as_str()));
+            }
+        }
+        upstream
+    }
+
+    /// The inputs `name` depends on, directly or indirectly.
+    pub fn upstream_inputs(&self, name: &str) -> BTreeSet<&str> {
+        self.upstream(name)
+            .into_iter()
+            .filter(|upstream| self.is_input(upstream))
+            .collect()
+    }
+
+    /// The expressions that depend on `name`, directly or indirectly, so would change if it
+    /// changed. `name` can be an input or an expression.
+    pub fn downstream(&self, name: &str) -> BTreeSet<&str> {
+        let mut downstream: BTreeSet<&str> = BTreeSet::new();
+        let mut to_visit: Vec<&str> = self
+            .readers
+            .get(name)
+            .or_else(|| self.dependents.get(name))
+            .into_iter()
+            .flatten()
+            .map(|dependent| dependent.as_str())
+            .collect();
+        while let Some(next) = to_visit.pop() {
+            if downstream.insert(next) {
+                to_visit.extend(
+                    self.dependents[next]
+                        .iter()
+                        .map(|dependent| dependent.as_str()),
+                );
+            }
+        }
+        downstream
+    }
+
+    /// Groups the expressions into layers: the first layer only depends on inputs, and every
+    /// later layer only on inputs and earlier layers, so the expressions within a layer are
+    /// independent of each other. Expressions in, or depending on, a cycle are left out.
+    pub fn layers(&self) -> Vec<Vec<&str>> {
+        let (order, _) = self.evaluation_order();
+        let mut layer_of: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut layers: Vec<Vec<&str>> = Vec::new();
+        for name in order {
+            let (name, _) = self.dependencies.get_key_value(&name).unwrap();
+            let layer = self.dependencies[name]
+                .iter()
+                .map(|dependency| layer_of[dependency.as_str()] + 1)
+                .max()
+                .unwrap_or(0);
+            layer_of.insert(name, layer);
+            if layers.len() <= layer {
+                layers.push(Vec::new());
+            }
+            layers[layer].push(name);
+        }
+        for layer in &mut layers {
+            layer.sort();
+        }
+        layers
+    }
+
+    /// Renders the graph in the Graphviz DOT language, with an edge from every expression or
+    /// input to each expression that references it. Inputs, intermediate expressions and outputs
+    /// have different shapes, and expressions and references in cycles are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let quote = |name: &str| format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+        let cycles = self.cycles();
+        let mut dot = String::from("digraph expressions {\n    rankdir=LR;\n");
+        for (name, kind) in self.nodes() {
+            let mut attributes = match kind {
+                NodeKind::Input => "shape=ellipse, style=filled, fillcolor=lightblue".to_string(),
+                NodeKind::Intermediate => "shape=box".to_string(),
+                NodeKind::Output => {
+                    "shape=box, style=filled, fillcolor=lightgreen, peripheries=2".to_string()
+                }
+            };
+            if cycles.contains_key(name) {
+                attributes.push_str(", color=red, fontcolor=red, penwidth=2");
+            }
+            dot.push_str(&format!("    {} [{}];\n", quote(name), attributes));
+        }
+        for (from, to) in self.edges() {
+            let attributes = if DependencyGraph::in_cycle(&cycles, from, to) {
+                " [color=red, penwidth=2]"
+            } else {
+                ""
+            };
+            dot.push_str(&format!(
+                "    {} -> {}{};\n",
+                quote(from),
+                quote(to),
+                attributes
+            ));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Renders the graph as a Mermaid flowchart, styled like `to_dot`. Nodes are given
+    /// generated ids, labelled with their names, as names are not always valid Mermaid ids.
+    pub fn to_mermaid(&self) -> String {
+        let cycles = self.cycles();
+        let nodes = self.nodes();
+        let ids: BTreeMap<&str, String> = nodes
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _))| (*name, format!("n{}", index)))
+            .collect();
+        let mut mermaid = String::from("flowchart LR\n");
+        for (name, kind) in &nodes {
+            let label = name.replace('"', "#quot;");
+            let node = match kind {
+                NodeKind::Input => format!("([\"{}\"])", label),
+                NodeKind::Intermediate => format!("[\"{}\"]", label),
+                NodeKind::Output => format!("[[\"{}\"]]", label),
+            };
+            mermaid.push_str(&format!("    {}{}\n", ids[name], node));
+        }
+        let mut cycle_edges: Vec<String> = Vec::new();
+        for (index, (from, to)) in self.edges().into_iter().enumerate() {
+            mermaid.push_str(&format!("    {} --> {}\n", ids[from], ids[to]));
+            if DependencyGraph::in_cycle(&cycles, from, to) {
+                cycle_edges.push(index.to_string());
+            }
+        }
+        mermaid.push_str("    classDef input fill:#dbeafe,stroke:#2563eb\n");
+        mermaid.push_str("    classDef output fill:#dcfce7,stroke:#16a34a,stroke-width:3px\n");
+        mermaid.push_str("    classDef cycle stroke:#dc2626,stroke-width:3px,color:#dc2626\n");
+        for (class, members) in [
+            (
+                "input",
+                nodes
+                    .iter()
+                    .filter(|(_, kind)| *kind == NodeKind::Input)
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>(),
+            ),
+            (
+                "output",
+                nodes
+                    .iter()
+                    .filter(|(_, kind)| *kind == NodeKind::Output)
+                    .map(|(name, _)| *name)
+                    .collect(),
+            ),
+            ("cycle", cycles.keys().copied().collect()),
+        ] {
+            if !members.is_empty() {
+                let members: Vec<&str> = members.iter().map(|name| ids[name].as_str()).collect();
+                mermaid.push_str(&format!("    class {} {}\n", members.join(","), class));
+            }
+        }
+        if !cycle_edges.is_empty() {
+            mermaid.push_str(&format!(
+                "    linkStyle {} stroke:#dc2626,stroke-width:3px\n",
+                cycle_edges.join(",")
+            ));
+        }
+        mermaid
+    }
+
+    /// Every input and expression, inputs first, with what kind of node it is.
+    fn nodes(&self) -> Vec<(&str, NodeKind)> {
+        let outputs: BTreeSet<&str> = self.outputs().collect();
+        let inputs = self.inputs().map(|name| (name, NodeKind::Input));
+        let expressions = self.expressions().map(|name| {
+            if outputs.contains(name) {
+                (name, NodeKind::Output)
+            } else {
+                (name, NodeKind::Intermediate)
+            }
+        });
+        inputs.chain(expressions).collect()
+    }
+
+    /// Every reference, from the referenced input or expression to the expression referencing it,
+    /// ordered by the referencing expression.
+    fn edges(&self) -> Vec<(&str, &str)> {
+        let mut edges: Vec<(&str, &str)> = Vec::new();
+        for (name, dependencies) in &self.dependencies {
+            let mut sources: Vec<&str> = self.inputs[name]
+                .iter()
+                .chain(dependencies)
+                .map(|source| source.as_str())
+                .collect();
+            sources.sort();
+            edges.extend(sources.into_iter().map(|source| (source, name.as_str())));
+        }
+        edges
+    }
+
+    /// The expressions that are part of a cycle, each with the first expression of its cycle, so
+    /// two expressions are in the same cycle when they have the same first expression.
+    fn cycles(&self) -> BTreeMap<&str, &str> {
+        let (_, blocked) = self.evaluation_order();
+        let blocked: BTreeSet<&str> = blocked.iter().map(|name| name.as_str()).collect();
+        let reachable: BTreeMap<&str, BTreeSet<&str>> = blocked
+            .iter()
+            .map(|name| (*name, self.reachable_from(name, &blocked)))
+            .collect();
+        let mut cycles: BTreeMap<&str, &str> = BTreeMap::new();
+        for (name, reachable_from_name) in &reachable {
+            // an expression is in a cycle when it can reach itself, and the cycle holds every
+            // expression it can reach that can reach it back
+            if let Some(member) = reachable_from_name.get(*name) {
+                let first = reachable_from_name
+                    .iter()
+                    .find(|other| reachable[*other].contains(name))
+                    .unwrap_or(member);
+                cycles.insert(member, first);
+            }
+        }
+        cycles
+    }
+
+    /// True if the reference from one expression to another is part of a cycle.
+    fn in_cycle(cycles: &BTreeMap<&str, &str>, from: &str, to: &str) -> bool {
+        cycles
+            .get(from)
+            .is_some_and(|first| cycles.get(to) == Some(first))
+    }
+
+    /// The expressions that `name` references directly.
+    pub(crate) fn dependencies(&self, name: &str) -> &BTreeSet<String> {
+        &self.dependencies[name]
+    }
+
+    /// Orders the expressions so that every expression comes after the expressions it depends on,
+    /// using Kahn's algorithm. Ties are broken by name so the order is stable.
+    ///
+    /// Returns the ordered expressions, followed by the expressions that could not be ordered
+    /// because they are part of, or depend on, a cycle.
+    pub(crate) fn evaluation_order(&self) -> (Vec<String>, Vec<String>) {
+        self.evaluation_order_within(&self.expressions().collect())
+    }
+
+    /// Orders the expressions in `scope` like `evaluation_order`, only following the references
+    /// between them, so the work is proportional to the size of the scope. The expressions they
+    /// reference outside the scope are taken to be evaluated already.
+    pub(crate) fn evaluation_order_within(
+        &self,
+        scope: &BTreeSet<&str>,
+    ) -> (Vec<String>, Vec<String>) {
+        let mut unresolved: BTreeMap<&str, usize> = scope
+            .iter()
+            .filter_map(|name| self.dependencies.get_key_value(*name))
+            .map(|(name, dependencies)| {
+                let within = dependencies
+                    .iter()
+                    .filter(|dependency| scope.contains(dependency.as_str()))
+                    .count();
+                (name.as_str(), within)
+            })
+            .collect();
+        let mut ready: BTreeSet<&str> = unresolved
+            .iter()
+            .filter(|(_, count)| **count == 0)
+            .map(|(name, _)| *name)
+            .collect();
+
+        let mut order: Vec<String> = Vec::with_capacity(unresolved.len());
+        while let Some(name) = ready.pop_first() {
+            unresolved.remove(name);
+            for dependent in &self.dependents[name] {
+                if let Some(count) = unresolved.get_mut(dependent.as_str()) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.insert(dependent);
+                    }
+                }
+            }
+            order.push(name.to_string());
+        }
+
+        let blocked = unresolved.keys().map(|name| name.to_string()).collect();
+        (order, blocked)
+    }
+
+    /// Finds the cycles among the blocked expressions returned by `evaluation_order`. Each
+    /// expression in a cycle gets an error naming a cycle through it and listing the expressions
+    /// that are blocked only because they depend on that cycle.
+    pub(crate) fn circular_references(
+        &self,
+        blocked: &[String],
+    ) -> BTreeMap<String, ExpressurError> {
+        let blocked: BTreeSet<&str> = blocked.iter().map(|name| name.as_str()).collect();
+        let reachable: BTreeMap<&str, BTreeSet<&str>> = blocked
+            .iter()
+            .map(|name| (*name, self.reachable_from(name, &blocked)))
+            .collect();
+
+        // the expressions in each cycle, keyed by the first of them
+        let mut cycles: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for name in &blocked {
+            if reachable[name].contains(name) {
+                let members: BTreeSet<&str> = reachable[name]
+                    .iter()
+                    .filter(|other| reachable[*other].contains(name))
+                    .copied()
+                    .collect();
+                cycles.insert(members.first().copied().unwrap_or(name), members);
+            }
+        }
+
+        let mut errors: BTreeMap<String, ExpressurError> = BTreeMap::new();
+        for (first, members) in &cycles {
+            let blocked_by_cycle: Vec<String> = blocked
+                .iter()
+                .filter(|name| !reachable[*name].contains(*name))
+                .filter(|name| reachable[*name].contains(first))
+                .map(|name| name.to_string())
+                .collect();
+            for member in members {
+                errors.insert(
+                    member.to_string(),
+                    ExpressurError::CircularReference {
+                        cycle: self.shortest_cycle(member, members),
+                        blocked: blocked_by_cycle.clone(),
+                    },
+                );
+            }
+        }
+        errors
+    }
+
+    /// Why each of the blocked expressions returned by `evaluation_order` could not be evaluated:
+    /// the expressions in a cycle fail with a circular reference, and the others are blocked by
+    /// the expressions they reference that have no result.
+    pub(crate) fn blocked_reasons(
+        &self,
+        blocked: &[String],
+        results: &BTreeMap<String, Decimal>,
+    ) -> BTreeMap<String, FailureReason> {
+        let mut circular_references = self.circular_references(blocked);
+        blocked
+            .iter()
+            .map(|name| {
+                let reason = match circular_references.remove(name) {
+                    Some(error) => FailureReason::Error(error),
+                    None => FailureReason::BlockedBy(
+                        self.dependencies(name)
+                            .iter()
+                            .filter(|dependency| !results.contains_key(*dependency))
+                            .cloned()
+                            .collect(),
+                    ),
+                };
+                (name.to_string(), reason)
+            })
+            .collect()
+    }
+
+    /// The expressions within `scope` that `name` depends on, directly or indirectly. This only
+    /// includes `name` itself when it is part of a cycle.
+    fn reachable_from<'a>(&'a self, name: &str, scope: &BTreeSet<&str>) -> BTreeSet<&'a str> {
+        let mut reachable: BTreeSet<&str> = BTreeSet::new();
+        let mut to_visit: Vec<&str> = self.dependencies[name].iter().map(|d| d.as_str()).collect();
+        while let Some(next) = to_visit.pop() {
+            if scope.contains(next) && reachable.insert(next) {
+                to_visit.extend(self.dependencies[next].iter().map(|d| d.as_str()));
+            }
+        }
+        reachable
+    }
+
+    /// The shortest path of references from `name` back to itself through the cycle's members,
+    /// such as ["a", "b", "a"].
+    fn shortest_cycle(&self, name: &str, members: &BTreeSet<&str>) -> Vec<String> {
+        let mut previous: BTreeMap<&str, &str> = BTreeMap::new();
+        let mut queue: VecDeque<&str> = VecDeque::from([name]);
+        while let Some(current) = queue.pop_front() {
+            for next in &self.dependencies[current] {
+                let next = next.as_str();
+                if !members.contains(next) {
+                    continue;
+                }
+                if next == name {
+                    let mut path = vec![name.to_string()];
+                    let mut step = current;
+                    while step != name {
+                        path.push(step.to_string());
+                        step = previous[step];
+                    }
+                    path.push(name.to_string());
+                    path.reverse();
+                    return path;
+                }
+                if !previous.contains_key(next) {
+                    previous.insert(next, current);
+                    queue.push_back(next);
+                }
+            }
+        }
+        vec![name.to_string()]
+    }
+}
+
+#[cfg(test)]
+fn compile_all(expressions: &[(&str, &str)]) -> BTreeMap<String, CompiledExpression> {
+    expressions
+        .iter()
+        .map(|(name, expression)| {
+            (
+                name.to_string(),
+                CompiledExpression::compile(expression).unwrap(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_evaluation_order() {
+    let compiled = compile_all(&[
+        ("extraindirection", "(aplusb/ cplusaplusb)"),
+        ("cplusaplusb", "c + aplusb"),
+        ("aplusb", "a + b"),
+    ]);
+    let graph = DependencyGraph::new(&compiled);
+    let (order, blocked) = graph.evaluation_order();
+    assert_eq!(order, vec!["aplusb", "cplusaplusb", "extraindirection"]);
+    assert!(blocked.is_empty());
+    // ordering the compiled expressions without the graph gives the same order
+    let (direct_order, direct_blocked) = evaluation_order(&compiled);
+    assert_eq!(direct_order, order);
+    assert_eq!(direct_blocked, blocked);
+
+    let independent = compile_all(&[("b", "x + 1"), ("a", "x * 2")]);
+    assert_eq!(evaluation_order(&independent), (vec!["a", "b"], Vec::new()));
+
+    let reversed = compile_all(&[("a", "b + 1"), ("b", "c * 2"), ("c", "x"), ("d", "a + c")]);
+    assert_eq!(
+        evaluation_order(&reversed),
+        (vec!["c", "b", "a", "d"], Vec::new())
+    );
+}
+
+#[test]
+fn test_evaluation_order_with_cycle() {
+    let compiled = compile_all(&[
+        ("a", "b + 1"),
+        ("b", "a * 2"),
+        ("c", "b + x"),
+        ("d", "x + 1"),
+        ("e", "e + d"),
+    ]);
+    let graph = DependencyGraph::new(&compiled);
+    let (order, blocked) = graph.evaluation_order();
+    assert_eq!(order, vec!["d"]);
+    assert_eq!(blocked, vec!["a", "b", "c", "e"]);
+    let (direct_order, direct_blocked) = evaluation_order(&compiled);
+    assert_eq!(direct_order, order);
+    assert_eq!(direct_blocked, blocked);
+}
+
+#[test]
+fn test_circular_references() {
+    let graph = DependencyGraph::new(&compile_all(&[
+        ("a", "b + 1"),
+        ("b", "a * 2"),
+        ("c", "b + x"),
+        ("d", "c + 1"),
+        ("e", "e + 1"),
+        ("f", "x + 1"),
+    ]));
+    let (_, blocked) = graph.evaluation_order();
+    let errors = graph.circular_references(&blocked);
+
+    let a_b_blocked = vec!["c".to_string(), "d".to_string()];
+    assert_eq!(
+        errors["a"],
+        ExpressurError::CircularReference {
+            cycle: vec!["a".to_string(), "b".to_string(), "a".to_string()],
+            blocked: a_b_blocked.clone(),
+        }
+    );
+    assert_eq!(
+        errors["b"],
+        ExpressurError::CircularReference {
+            cycle: vec!["b".to_string(), "a".to_string(), "b".to_string()],
+            blocked: a_b_blocked.clone(),
+        }
+    );
+    assert!(!errors.contains_key("c"));
+    assert!(!errors.contains_key("d"));
+    assert_eq!(
+        errors["e"],
+        ExpressurError::CircularReference {
+            cycle: vec!["e".to_string(), "e".to_string()],
+            blocked: vec![],
+        }
+    );
+    assert!(!errors.contains_key("f"));
+}
+
+#[test]
+fn test_dependency_graph_queries() {
+    let expressions: BTreeMap<String, String> = [
+        ("aplusb", "a + b"),
+        ("cplusaplusb", "c + aplusb"),
+        ("extraindirection", "(aplusb/ cplusaplusb)"),
+        ("scaled", "max(a, 1) * factor"),
+        ("cycle1", "cycle2 + a"),
+        ("cycle2", "cycle1"),
+    ]
+    .iter()
+    .map(|(name, expression)| (name.to_string(), expression.to_string()))
+    .collect();
+    let graph = DependencyGraph::build(&expressions).unwrap();
+    let set =
+        |names: &[&'static str]| -> BTreeSet<&'static str> { names.iter().copied().collect() };
+
+    assert_eq!(
+        graph.inputs().collect::<Vec<_>>(),
+        vec!["a", "b", "c", "factor"]
+    );
+    assert_eq!(
+        graph.outputs().collect::<Vec<_>>(),
+        vec!["extraindirection", "scaled"]
+    );
+    assert!(graph.is_input("factor"));
+    assert!(!graph.is_input("aplusb"));
+    assert!(!graph.is_input("max"));
+
+    assert_eq!(
+        graph.downstream("a"),
+        set(&[
+            "aplusb",
+            "cplusaplusb",
+            "cycle1",
+            "cycle2",
+            "extraindirection",
+            "scaled"
+        ])
+    );
+    assert_eq!(
+        graph.downstream("c"),
+        set(&["cplusaplusb", "extraindirection"])
+    );
+    assert_eq!(
+        graph.downstream("aplusb"),
+        set(&["cplusaplusb", "extraindirection"])
+    );
+    assert_eq!(graph.downstream("extraindirection"), set(&[]));
+    assert_eq!(graph.downstream("unknown"), set(&[]));
+
+    assert_eq!(
+        graph.upstream("extraindirection"),
+        set(&["a", "aplusb", "b", "c", "cplusaplusb"])
+    );
+    assert_eq!(
+        graph.upstream_inputs("extraindirection"),
+        set(&["a", "b", "c"])
+    );
+    assert_eq!(graph.upstream("cycle1"), set(&["a", "cycle1", "cycle2"]));
+    assert_eq!(graph.upstream("a"), set(&[]));
+
+    assert_eq!(
+        graph.layers(),
+        vec![
+            vec!["aplusb", "scaled"],
+            vec!["cplusaplusb"],
+            vec!["extraindirection"]
+        ]
+    );
+}
+
+#[test]
+fn test_dependency_graph_build_errors() {
+    let expressions: BTreeMap<String, String> = [
+        ("fine".to_string(), "a + b".to_string()),
+        ("broken".to_string(), "a # b".to_string()),
+    ]
+    .iter()
+    .cloned()
+    .collect();
+    assert_eq!(
+        DependencyGraph::build(&expressions),
+        Err(ExpressionFailure {
+            name: "broken".to_string(),
+            expression: "a # b".to_string(),
+            reason: FailureReason::Error(ExpressurError::SyntaxError {
+                token: "#".to_string(),
+                position: 2
+            }),
+        })
+    );
+}
+
+#[cfg(test)]
+fn graph_of(expressions: &[(&str, &str)]) -> DependencyGraph {
+    let expressions: BTreeMap<String, String> = expressions
+        .iter()
+        .map(|(name, expression)| (name.to_string(), expression.to_string()))
+        .collect();
+    DependencyGraph::build(&expressions).unwrap()
+}
+
+#[test]
+fn test_to_dot() {
+    let graph = graph_of(&[
+        ("aplusb", "a + b"),
+        ("total", "aplusb * 2"),
+        ("x", "y + a"),
+        ("y", "x"),
+    ]);
+    let expected = r#"digraph expressions {
+    rankdir=LR;
+    "a" [shape=ellipse, style=filled, fillcolor=lightblue];
+    "b" [shape=ellipse, style=filled, fillcolor=lightblue];
+    "aplusb" [shape=box];
+    "total" [shape=box, style=filled, fillcolor=lightgreen, peripheries=2];
+    "x" [shape=box, color=red, fontcolor=red, penwidth=2];
+    "y" [shape=box, color=red, fontcolor=red, penwidth=2];
+    "a" -> "aplusb";
+    "b" -> "aplusb";
+    "aplusb" -> "total";
+    "a" -> "x";
+    "y" -> "x" [color=red, penwidth=2];
+    "x" -> "y" [color=red, penwidth=2];
+}
+"#;
+    assert_eq!(graph.to_dot(), expected);
+}
+
+#[test]
+fn test_to_mermaid() {
+    let graph = graph_of(&[
+        ("aplusb", "a + b"),
+        ("total", "aplusb * 2"),
+        ("x", "y + a"),
+        ("y", "x"),
+    ]);
+    let expected = r#"flowchart LR
+    n0(["a"])
+    n1(["b"])
+    n2["aplusb"]
+    n3[["total"]]
+    n4["x"]
+    n5["y"]
+    n0 --> n2
+    n1 --> n2
+    n2 --> n3
+    n0 --> n4
+    n5 --> n4
+    n4 --> n5
+    classDef input fill:#dbeafe,stroke:#2563eb
+    classDef output fill:#dcfce7,stroke:#16a34a,stroke-width:3px
+    classDef cycle stroke:#dc2626,stroke-width:3px,color:#dc2626
+    class n0,n1 input
+    class n3 output
+    class n4,n5 cycle
+    linkStyle 4,5 stroke:#dc2626,stroke-width:3px
+"#;
+    assert_eq!(graph.to_mermaid(), expected);
+}
+
+#[test]
+fn test_edges_between_cycles_are_not_highlighted() {
+    let graph = graph_of(&[("a", "b"), ("b", "a"), ("c", "d"), ("d", "c + a")]);
+    let dot = graph.to_dot();
+    let edges: Vec<&str> = dot.lines().filter(|line| line.contains("->")).collect();
+    assert_eq!(
+        edges,
+        vec![
+            r#"    "b" -> "a" [color=red, penwidth=2];"#,
+            r#"    "a" -> "b" [color=red, penwidth=2];"#,
+            r#"    "d" -> "c" [color=red, penwidth=2];"#,
+            r#"    "a" -> "d";"#,
+            r#"    "c" -> "d" [color=red, penwidth=2];"#,
+        ]
+    );
+    assert!(graph
+        .to_mermaid()
+        .contains("    linkStyle 0,1,2,4 stroke:#dc2626,stroke-width:3px\n"));
+}
+
+#[test]
+fn test_incremental_updates_match_a_rebuild() {
+    let mut compiled = compile_all(&[("a", "b + x"), ("c", "a * 2"), ("d", "y")]);
+    let mut graph = DependencyGraph::new(&compiled);
+    let mut update = |name: &str, expression: Option<&str>| {
+        match expression {
+            Some(expression) => {
+                let expression = CompiledExpression::compile(expression).unwrap();
+                graph.insert(name, expression.variables());
+                compiled.insert(name.to_string(), expression);
+            }
+            None => {
+                graph.remove(name);
+                compiled.remove(name);
+            }
+        }
+        assert_eq!(graph, DependencyGraph::new(&compiled), "{}", name);
+    };
+    // an input becomes an expression
+    update("b", Some("x + 1"));
+    // references change, an input is no longer read, and one is read for the first time
+    update("a", Some("b * c"));
+    update("d", Some("z"));
+    update("e", Some("e + 1"));
+    // an expression becomes an input again
+    update("c", None);
+    update("b", None);
+    update("e", None);
+    update("missing", None);
+}
+
+#[test]
+fn test_evaluation_order_within() {
+    let graph = DependencyGraph::new(&compile_all(&[
+        ("aplusb", "a + b"),
+        ("cplusaplusb", "c + aplusb"),
+        ("extraindirection", "(aplusb/ cplusaplusb)"),
+        ("x", "y + c"),
+        ("y", "x"),
+        ("other", "a"),
+    ]));
+    let scope: BTreeSet<&str> = graph.downstream("c");
+    let (order, blocked) = graph.evaluation_order_within(&scope);
+    assert_eq!(order, vec!["cplusaplusb", "extraindirection"]);
+    assert_eq!(blocked, vec!["x", "y"]);
+}
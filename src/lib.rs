@@ -1,12 +1,38 @@
+mod analysis;
+mod compiled;
+mod context;
+mod error;
 mod evaluator;
+mod explain;
+mod functions;
+mod graph;
+mod model;
 mod tokenizer;  
 pub mod prelude{
     pub use std::collections::BTreeMap;
     pub use rust_decimal::Decimal;
     pub use rust_decimal_macros::*;
+    pub use crate::analysis::*;
+    pub use crate::compiled::*;
+    pub use crate::context::*;
+    pub use crate::error::*;
     pub use crate::evaluator::*;
+    pub use crate::explain::{explain_expression, explain_expressions, Explanation, ExplanationSet, Operand, Operation, Step};
+    pub use crate::functions::Arity;
+    pub use crate::graph::DependencyGraph;
+    pub use crate::model::{Change, Model, SubscriptionId};
+    pub use crate::tokenizer::{tokenize, Token};
 }
 
 pub mod expressur{
+    pub use crate::analysis::*;
+    pub use crate::compiled::*;
+    pub use crate::context::*;
+    pub use crate::error::*;
     pub use crate::evaluator::*;
+    pub use crate::explain::{explain_expression, explain_expressions, Explanation, ExplanationSet, Operand, Operation, Step};
+    pub use crate::functions::Arity;
+    pub use crate::graph::DependencyGraph;
+    pub use crate::model::{Change, Model, SubscriptionId};
+    pub use crate::tokenizer::{tokenize, Token};
 }
\ No newline at end of file
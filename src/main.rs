@@ -1,26 +1,118 @@
 use std::env;
 use std::collections::BTreeMap;
+use std::io::Read;
 use expressur::expressur::*;
 
+const USAGE: &str = "Usage: expressur \"<expression>\"\n       expressur graph [--dot|--mermaid] [<file>]";
 
-/// Evaluates an arithmetic expression passed on the command line.
-/// 
+/// Evaluates an arithmetic expression passed on the command line, or draws the dependency graph
+/// of a set of expressions.
+///
 /// # Arguments
-/// Any string that's a value or an arithmetic expression.
-/// 
+/// Any string that's a value or an arithmetic expression, or `graph` followed by an optional
+/// `--dot` (the default) or `--mermaid` and the file to read the expressions from. Each line of
+/// the file is `name = expression`; blank lines and lines starting with # are ignored. Without a
+/// file the expressions are read from standard input.
+///
 /// # Examples
-/// 
+///
 /// ```
 /// $ cargo run -- "1 + 2"
 /// 3
+/// $ cargo run -- graph --mermaid model.txt
+/// flowchart LR
+/// ...
 /// ```
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let expression = args[1].to_string();
+    let expression = match args.get(1) {
+        Some(command) if command == "graph" => {
+            graph(&args[2..]);
+            return;
+        }
+        Some(expression) => expression.to_string(),
+        None => {
+            println!("{}", USAGE);
+            return;
+        }
+    };
     let context = BTreeMap::new();
     let result = evaluate_expression(&expression, &context);
     match result {
         Ok(value) => println!("{}", value),
         Err(error) => println!("{}", error)
-    } 
+    }
+}
+
+/// Prints the dependency graph of the expressions in a file, or standard input, as DOT or Mermaid.
+fn graph(args: &[String]) {
+    let mut mermaid = false;
+    let mut file = None;
+    for arg in args {
+        match arg.as_str() {
+            "--dot" => mermaid = false,
+            "--mermaid" => mermaid = true,
+            _ if arg.starts_with("--") => exit_with_error(&format!("Unknown option: {}\n{}", arg, USAGE)),
+            _ if file.is_some() => exit_with_error(&format!("Unexpected argument: {}\n{}", arg, USAGE)),
+            _ => file = Some(arg),
+        }
+    }
+    let source = match file {
+        Some(file) => std::fs::read_to_string(file),
+        None => {
+            let mut source = String::new();
+            std::io::stdin().read_to_string(&mut source).map(|_| source)
+        }
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(error) => exit_with_error(&error.to_string()),
+    };
+
+    let expressions = match parse_expressions(&source) {
+        Ok(expressions) => expressions,
+        Err(message) => exit_with_error(&message),
+    };
+    match DependencyGraph::build(&expressions) {
+        Ok(graph) if mermaid => print!("{}", graph.to_mermaid()),
+        Ok(graph) => print!("{}", graph.to_dot()),
+        Err(failure) => exit_with_error(&failure.to_string()),
+    }
+}
+
+/// Reads a set of expressions written as `name = expression` lines, skipping blank lines and lines starting with #.
+fn parse_expressions(source: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut expressions: BTreeMap<String, String> = BTreeMap::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // the first "=" separates the name, as expressions can contain "=" themselves
+        match line.split_once('=') {
+            Some((name, expression)) if !name.trim().is_empty() => {
+                expressions.insert(name.trim().to_string(), expression.trim().to_string());
+            }
+            _ => return Err(format!("Line {}: expected name = expression", number + 1)),
+        }
+    }
+    Ok(expressions)
+}
+
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+#[test]
+fn test_parse_expressions() {
+    let source = "# a model\n\naplusb = a + b\n  same =  aplusb = 3  \n";
+    let expressions = parse_expressions(source).unwrap();
+    assert_eq!(expressions.len(), 2);
+    assert_eq!(expressions["aplusb"], "a + b");
+    // only the first "=" separates the name
+    assert_eq!(expressions["same"], "aplusb = 3");
+
+    assert_eq!(parse_expressions("a = 1\nb + 2"), Err("Line 2: expected name = expression".to_string()));
+    assert_eq!(parse_expressions(" = 1"), Err("Line 1: expected name = expression".to_string()));
 }
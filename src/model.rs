@@ -0,0 +1,524 @@
+use std::collections::BTreeSet;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::evaluator::evaluate_member;
+use crate::graph::DependencyGraph;
+use crate::prelude::*;
+
+/// A live set of named formulas and the inputs they read, like a spreadsheet. Formulas are
+/// compiled once, their values are kept, and changing an input or a formula only recalculates
+/// the formulas downstream of it.
+///
+/// # Examples
+///
+/// ```
+/// use expressur::expressur::*;
+/// use rust_decimal_macros::dec;
+///
+/// let mut model = Model::new();
+/// model.set_input("a", dec!(1));
+/// model.set_input("b", dec!(2));
+/// model.set_formula("aplusb", "a + b").unwrap();
+/// model.set_formula("doubled", "aplusb * 2").unwrap();
+/// model.set_formula("other", "b * 10").unwrap();
+///
+/// // only the formulas that read a are recalculated
+/// let changed = model.set_input("a", dec!(5));
+/// assert_eq!(changed, ["aplusb", "doubled"].map(String::from).into());
+/// assert_eq!(model.value("doubled"), Some(dec!(14)));
+/// ```
+#[derive(Debug)]
+pub struct Model {
+    evaluator: Evaluator,
+    inputs: BTreeMap<String, Decimal>,
+    /// The source text of each formula.
+    formulas: BTreeMap<String, String>,
+    compiled: BTreeMap<String, CompiledExpression>,
+    graph: DependencyGraph,
+    /// The current value of each formula that could be evaluated.
+    values: BTreeMap<String, Decimal>,
+    /// Why each of the other formulas could not be evaluated.
+    failures: BTreeMap<String, FailureReason>,
+    subscriptions: Vec<Subscription>,
+    next_subscription: usize,
+}
+
+/// A change to the value of a formula. A value of None means the formula could not be evaluated,
+/// or did not exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub name: String,
+    pub old: Option<Decimal>,
+    pub new: Option<Decimal>,
+}
+
+/// Identifies a subscription so it can be cancelled with `Model::unsubscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SubscriptionId(usize);
+
+/// Where the changes to a formula are sent.
+enum Subscriber {
+    Callback(Box<dyn FnMut(&Change) + Send>),
+    Channel(Sender<Change>),
+}
+
+struct Subscription {
+    id: SubscriptionId,
+    name: String,
+    subscriber: Subscriber,
+}
+
+impl std::fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.subscriber {
+            Subscriber::Callback(_) => "Callback",
+            Subscriber::Channel(_) => "Channel",
+        };
+        f.debug_struct("Subscription")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("subscriber", &kind)
+            .finish()
+    }
+}
+
+impl Default for Model {
+    fn default() -> Model {
+        Model::with_evaluator(Evaluator::new())
+    }
+}
+
+impl Model {
+    /// Creates an empty model whose formulas can call the built-in functions.
+    pub fn new() -> Model {
+        Model::default()
+    }
+
+    /// Creates an empty model whose formulas are compiled by the evaluator, so they can call its
+    /// custom functions.
+    pub fn with_evaluator(evaluator: Evaluator) -> Model {
+        Model {
+            evaluator,
+            inputs: BTreeMap::new(),
+            formulas: BTreeMap::new(),
+            compiled: BTreeMap::new(),
+            graph: DependencyGraph::new(&BTreeMap::<String, _>::new()),
+            values: BTreeMap::new(),
+            failures: BTreeMap::new(),
+            subscriptions: Vec::new(),
+            next_subscription: 0,
+        }
+    }
+
+    /// Sets the value of an input and recalculates the formulas that depend on it.
+    ///
+    /// # Returns
+    /// The names of the formulas whose values changed, including formulas that now have a value
+    /// and did not before, or the other way around.
+    pub fn set_input(&mut self, name: &str, value: Decimal) -> BTreeSet<String> {
+        if self.inputs.insert(name.to_string(), value) == Some(value) {
+            return BTreeSet::new();
+        }
+        let changes = self.recalculate(self.downstream(name));
+        self.publish(changes)
+    }
+
+    /// Removes an input and recalculates the formulas that depend on it, which now fail.
+    ///
+    /// # Returns
+    /// The names of the formulas whose values changed.
+    pub fn remove_input(&mut self, name: &str) -> BTreeSet<String> {
+        if self.inputs.remove(name).is_none() {
+            return BTreeSet::new();
+        }
+        let changes = self.recalculate(self.downstream(name));
+        self.publish(changes)
+    }
+
+    /// Adds or replaces a formula and recalculates it and the formulas that depend on it. A formula
+    /// replaces any input with the same name.
+    ///
+    /// # Returns
+    /// The names of the formulas whose values changed, including this one.
+    ///
+    /// # Errors
+    /// If the formula cannot be compiled, the compile error is returned and the model is not
+    /// changed.
+    pub fn set_formula(
+        &mut self,
+        name: &str,
+        formula: &str,
+    ) -> Result<BTreeSet<String>, ExpressurError> {
+        let compiled = self.evaluator.compile(formula)?;
+        // the formulas that used to depend on this one, in case its new version breaks a cycle
+        let mut affected = self.downstream(name);
+        self.formulas.insert(name.to_string(), formula.to_string());
+        self.graph.insert(name, compiled.variables());
+        self.compiled.insert(name.to_string(), compiled);
+        affected.insert(name.to_string());
+        affected.extend(self.downstream(name));
+        let changes = self.recalculate(affected);
+        Ok(self.publish(changes))
+    }
+
+    /// Removes a formula. Formulas that referenced it read an input with the same name instead, if
+    /// there is one.
+    ///
+    /// # Returns
+    /// The names of the formulas whose values changed, including this one if it had a value.
+    pub fn remove_formula(&mut self, name: &str) -> BTreeSet<String> {
+        if self.formulas.remove(name).is_none() {
+            return BTreeSet::new();
+        }
+        let affected = self.downstream(name);
+        self.compiled.remove(name);
+        self.graph.remove(name);
+        self.failures.remove(name);
+        let previous = self.values.remove(name);
+        let mut changes = self.recalculate(affected);
+        if previous.is_some() {
+            changes.push(Change {
+                name: name.to_string(),
+                old: previous,
+                new: None,
+            });
+            changes.sort_by(|x, y| x.name.cmp(&y.name));
+        }
+        self.publish(changes)
+    }
+
+    /// The current value of a formula, or of an input. None for a formula that could not be
+    /// evaluated, or a name that is in neither.
+    pub fn value(&self, name: &str) -> Option<Decimal> {
+        if self.formulas.contains_key(name) {
+            self.values.get(name).copied()
+        } else {
+            self.inputs.get(name).copied()
+        }
+    }
+
+    /// The current values of the formulas that could be evaluated.
+    pub fn values(&self) -> &BTreeMap<String, Decimal> {
+        &self.values
+    }
+
+    /// Why a formula could not be evaluated, or None if it was.
+    pub fn failure(&self, name: &str) -> Option<&FailureReason> {
+        self.failures.get(name)
+    }
+
+    /// The source text of a formula.
+    pub fn formula(&self, name: &str) -> Option<&str> {
+        self.formulas.get(name).map(|formula| formula.as_str())
+    }
+
+    /// The dependencies between the formulas, and the inputs they read.
+    pub fn graph(&self) -> &DependencyGraph {
+        &self.graph
+    }
+
+    /// Calls the callback with every change to the value of the formula, after the model has
+    /// been recalculated.
+    pub fn subscribe<F>(&mut self, name: &str, callback: F) -> SubscriptionId
+    where
+        F: FnMut(&Change) + Send + 'static,
+    {
+        self.add_subscription(name, Subscriber::Callback(Box::new(callback)))
+    }
+
+    /// Sends every change to the value of the formula to the returned receiver. The subscription
+    /// ends when the receiver is dropped.
+    pub fn subscribe_channel(&mut self, name: &str) -> (SubscriptionId, Receiver<Change>) {
+        let (sender, receiver) = channel();
+        (
+            self.add_subscription(name, Subscriber::Channel(sender)),
+            receiver,
+        )
+    }
+
+    /// Cancels a subscription. Returns false if it had already ended.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let count = self.subscriptions.len();
+        self.subscriptions
+            .retain(|subscription| subscription.id != id);
+        self.subscriptions.len() != count
+    }
+
+    fn add_subscription(&mut self, name: &str, subscriber: Subscriber) -> SubscriptionId {
+        let id = SubscriptionId(self.next_subscription);
+        self.next_subscription += 1;
+        self.subscriptions.push(Subscription {
+            id,
+            name: name.to_string(),
+            subscriber,
+        });
+        id
+    }
+
+    /// Notifies the subscribers of the changes, and returns the names of the changed formulas.
+    fn publish(&mut self, changes: Vec<Change>) -> BTreeSet<String> {
+        for change in &changes {
+            // a channel whose receiver was dropped ends its subscription
+            self.subscriptions.retain_mut(|subscription| {
+                if subscription.name != change.name {
+                    return true;
+                }
+                match &mut subscription.subscriber {
+                    Subscriber::Callback(callback) => {
+                        callback(change);
+                        true
+                    }
+                    Subscriber::Channel(sender) => sender.send(change.clone()).is_ok(),
+                }
+            });
+        }
+        changes.into_iter().map(|change| change.name).collect()
+    }
+
+    fn downstream(&self, name: &str) -> BTreeSet<String> {
+        self.graph
+            .downstream(name)
+            .into_iter()
+            .map(|name| name.to_string())
+            .collect()
+    }
+
+    /// Re-evaluates the affected formulas, in dependency order, and returns the changes to their
+    /// values, ordered by name.
+    fn recalculate(&mut self, affected: BTreeSet<String>) -> Vec<Change> {
+        let previous: BTreeMap<String, Option<Decimal>> = affected
+            .iter()
+            .map(|name| (name.to_string(), self.values.remove(name)))
+            .collect();
+        for name in &affected {
+            self.failures.remove(name);
+        }
+
+        // the affected formulas include everything downstream of them, so the rest of the model
+        // does not need to be ordered again
+        let scope: BTreeSet<&str> = affected.iter().map(|name| name.as_str()).collect();
+        let (order, blocked) = self.graph.evaluation_order_within(&scope);
+        for name in order {
+            match evaluate_member(
+                &self.compiled[&name],
+                &self.formulas,
+                &self.values,
+                &self.inputs,
+                None,
+            ) {
+                Ok(value) => {
+                    self.values.insert(name, value);
+                }
+                Err(reason) => {
+                    self.failures.insert(name, reason);
+                }
+            }
+        }
+        self.failures
+            .extend(self.graph.blocked_reasons(&blocked, &self.values));
+
+        previous
+            .into_iter()
+            .filter(|(name, old)| self.values.get(name) != old.as_ref())
+            .map(|(name, old)| Change {
+                new: self.values.get(&name).copied(),
+                name,
+                old,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_model_recalculates_downstream_only() {
+    let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = calls.clone();
+    let mut evaluator = Evaluator::new();
+    evaluator.register_function("counted", Arity::Fixed(1), move |args| {
+        counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Ok(args[0])
+    });
+    let mut model = Model::with_evaluator(evaluator);
+    model.set_input("a", dec!(1));
+    model.set_input("b", dec!(2));
+    model.set_input("c", dec!(4));
+    model.set_formula("aplusb", "a + b").unwrap();
+    model.set_formula("cplusaplusb", "c + aplusb").unwrap();
+    model
+        .set_formula("extraindirection", "aplusb / cplusaplusb")
+        .unwrap();
+    model.set_formula("unrelated", "counted(b)").unwrap();
+    assert_eq!(
+        model.value("extraindirection").unwrap().round_dp(3),
+        dec!(0.429)
+    );
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    let changed = model.set_input("c", dec!(9));
+    assert_eq!(
+        changed,
+        ["cplusaplusb", "extraindirection"].map(String::from).into()
+    );
+    assert_eq!(model.value("cplusaplusb"), Some(dec!(12)));
+    assert_eq!(model.value("extraindirection"), Some(dec!(0.25)));
+    // the formula that does not read c was not evaluated again
+    assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+    // setting the same value changes nothing
+    assert!(model.set_input("c", dec!(9)).is_empty());
+
+    // a formula whose value stays the same is not reported
+    model.set_formula("sign", "aplusb > 0").unwrap();
+    let changed = model.set_input("a", dec!(2));
+    assert_eq!(
+        changed,
+        ["aplusb", "cplusaplusb", "extraindirection"]
+            .map(String::from)
+            .into()
+    );
+    assert_eq!(model.value("sign"), Some(dec!(1)));
+
+    let changed = model.set_formula("aplusb", "a - b").unwrap();
+    assert_eq!(
+        changed,
+        ["aplusb", "cplusaplusb", "extraindirection", "sign"]
+            .map(String::from)
+            .into()
+    );
+    assert_eq!(model.value("aplusb"), Some(dec!(0)));
+    assert_eq!(model.value("extraindirection"), Some(dec!(0)));
+    assert_eq!(model.value("a"), Some(dec!(2)));
+}
+
+#[test]
+fn test_model_failures() {
+    let mut model = Model::new();
+    model.set_input("a", dec!(1));
+    model.set_formula("x", "a / b").unwrap();
+    model.set_formula("y", "x + 1").unwrap();
+    assert_eq!(model.value("x"), None);
+    assert_eq!(
+        model.failure("x"),
+        Some(&FailureReason::MissingVariables(vec!["b".to_string()]))
+    );
+    assert_eq!(
+        model.failure("y"),
+        Some(&FailureReason::BlockedBy(vec!["x".to_string()]))
+    );
+
+    let changed = model.set_input("b", dec!(4));
+    assert_eq!(changed, ["x", "y"].map(String::from).into());
+    assert_eq!(model.value("y"), Some(dec!(1.25)));
+    assert_eq!(model.failure("y"), None);
+
+    let changed = model.set_input("b", dec!(0));
+    assert_eq!(changed, ["x", "y"].map(String::from).into());
+    assert!(matches!(
+        model.failure("x"),
+        Some(FailureReason::Error(ExpressurError::DivisionByZero { .. }))
+    ));
+
+    // a formula that does not compile leaves the model as it was
+    assert_eq!(
+        model.set_formula("x", "a +"),
+        Err(ExpressurError::SyntaxError {
+            token: "+".to_string(),
+            position: 2
+        })
+    );
+    assert_eq!(model.formula("x"), Some("a / b"));
+
+    // closing a cycle fails its members, and breaking it recovers them
+    model.set_input("b", dec!(2));
+    let changed = model.set_formula("x", "y * 2").unwrap();
+    assert_eq!(changed, ["x", "y"].map(String::from).into());
+    assert!(matches!(
+        model.failure("x"),
+        Some(FailureReason::Error(
+            ExpressurError::CircularReference { .. }
+        ))
+    ));
+    let changed = model.set_formula("x", "a * 2").unwrap();
+    assert_eq!(changed, ["x", "y"].map(String::from).into());
+    assert_eq!(model.value("y"), Some(dec!(3)));
+
+    let changed = model.remove_input("a");
+    assert_eq!(changed, ["x", "y"].map(String::from).into());
+    let changed = model.remove_formula("x");
+    assert!(changed.is_empty());
+    assert_eq!(
+        model.failure("y"),
+        Some(&FailureReason::MissingVariables(vec!["x".to_string()]))
+    );
+    // the graph was updated one formula at a time
+    assert_eq!(model.graph, DependencyGraph::new(&model.compiled));
+}
+
+#[test]
+fn test_model_subscriptions() {
+    let mut model = Model::new();
+    model.set_input("a", dec!(1));
+    model.set_input("b", dec!(2));
+    model.set_formula("aplusb", "a + b").unwrap();
+    model.set_formula("positive", "aplusb > 0").unwrap();
+
+    let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let log = seen.clone();
+    let callback = model.subscribe("aplusb", move |change| {
+        log.lock().unwrap().push(change.clone())
+    });
+    let (_, positive) = model.subscribe_channel("positive");
+    let (_, dropped) = model.subscribe_channel("aplusb");
+    drop(dropped);
+
+    model.set_input("a", dec!(5));
+    model.set_input("a", dec!(-5));
+    model.set_input("b", dec!(0));
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![
+            Change {
+                name: "aplusb".to_string(),
+                old: Some(dec!(3)),
+                new: Some(dec!(7))
+            },
+            Change {
+                name: "aplusb".to_string(),
+                old: Some(dec!(7)),
+                new: Some(dec!(-3))
+            },
+            Change {
+                name: "aplusb".to_string(),
+                old: Some(dec!(-3)),
+                new: Some(dec!(-5))
+            },
+        ]
+    );
+    // only the changes to positive's value are sent
+    assert_eq!(
+        positive.try_iter().collect::<Vec<_>>(),
+        vec![Change {
+            name: "positive".to_string(),
+            old: Some(dec!(1)),
+            new: Some(dec!(0))
+        }]
+    );
+    // the subscription whose receiver was dropped has ended
+    assert_eq!(model.subscriptions.len(), 2);
+
+    // a formula that stops evaluating changes to None
+    model.remove_input("b");
+    assert_eq!(
+        seen.lock().unwrap().last(),
+        Some(&Change {
+            name: "aplusb".to_string(),
+            old: Some(dec!(-5)),
+            new: None
+        })
+    );
+
+    assert!(model.unsubscribe(callback));
+    assert!(!model.unsubscribe(callback));
+    model.set_input("b", dec!(1));
+    assert_eq!(seen.lock().unwrap().len(), 4);
+}
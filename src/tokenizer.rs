@@ -1,83 +1,221 @@
-use crate::output_token;
+use std::ops::Range;
 
-pub fn tokenize(expression: &str) -> Vec<String>{
+use rust_decimal::Decimal;
 
-    let mut output: Vec<String> = Vec::new();
-    let mut last_char = char::default();
+use crate::error::ExpressurError;
 
-    let mut current_token: String = String::new();
-    let expression_len = expression.chars().count();
-    for i in 0..expression_len{
-        let c = expression.chars().nth(i).unwrap();
+/// A token of an expression, with the byte range of the expression it was read from.
+///
+/// ```
+/// use expressur::expressur::{tokenize, Token};
+///
+/// let expression = "max(a, -1)";
+/// let tokens = tokenize(expression).unwrap();
+/// assert_eq!(tokens[0], Token::Identifier(0..3));
+/// assert_eq!(tokens[4].text(expression), "-1");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    /// A numeric literal, including a sign written directly on it, such as -1.5.
+    Number(Range<usize>),
+    /// A variable or function name.
+    Identifier(Range<usize>),
+    /// An operator, including the word operators "and", "or" and "not".
+    Operator(Range<usize>),
+    LeftParenthesis(Range<usize>),
+    RightParenthesis(Range<usize>),
+    /// The separator between function arguments.
+    Comma(Range<usize>),
+    /// The "?" of a conditional.
+    Question(Range<usize>),
+    /// The ":" of a conditional.
+    Colon(Range<usize>),
+}
 
-        if is_whitespace(c){
-            current_token = output_token!(output, current_token);
-        }
-        else if is_token_character(c){
-            current_token.push(c);
+impl Token {
+    /// The byte range of the expression the token was read from.
+    pub fn span(&self) -> Range<usize> {
+        match self {
+            Token::Number(span)
+            | Token::Identifier(span)
+            | Token::Operator(span)
+            | Token::LeftParenthesis(span)
+            | Token::RightParenthesis(span)
+            | Token::Comma(span)
+            | Token::Question(span)
+            | Token::Colon(span) => span.clone(),
         }
-        else if c == '(' || c == ')' || c =='*' || c =='/' || c =='^' || c =='%' || c =='='{
-            current_token = output_token!(output, current_token);
-            output.push(c.to_string());
-        }
-        else if c == '-' || c == '+' {
-                // could be indicating negative/positive number or operator
-                // if it is the first valid token in an expression/subexpression, its a negative/positive number
-                // if it is the first valid token after an operator, its a negative/positive number
-                // if it is the first valid token after an open parenthesis, its a negative/positive number
-                // if it is the first valid token after a decimal point, its a negative/positive number
-                // if it is the first valid token after an identifier or number, its an operator        
-            
-            let last_token = match output.last() {
-                Some(token) => token.to_string(),
-                None => "".to_string(),
-            };
-            if last_char == char::default() {
-                current_token.push(c);
-            }            
-            else if expression_len > i {
-                let next = expression.chars().nth(i+1).unwrap();
-
-                if (is_whitespace(c) || is_operator(next) || next =='(')
-                    ||
-                    (is_number(next)  && (last_token != "(" || output.is_empty()) && !is_operator_str(last_token)) 
-                {
-                    current_token = output_token!(output, current_token);
-                    output.push(c.to_string());
+    }
+
+    /// The text of the token in the expression it was read from.
+    pub fn text<'a>(&self, expression: &'a str) -> &'a str {
+        &expression[self.span()]
+    }
+}
+
+/// Splits an expression into tokens in a single pass.
+pub fn tokenize(expression: &str) -> Result<Vec<Token>, ExpressurError> {
+    let mut output: Vec<Token> = Vec::new();
+    let mut last_char = char::default();
+
+    // the byte offset at which the number or identifier being read starts
+    let mut current_token: Option<usize> = None;
+    let mut chars = expression.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|(_, next)| *next);
+
+        if is_whitespace(c) {
+            end_token(expression, &mut output, &mut current_token, i);
+        } else if is_token_character(c) {
+            current_token.get_or_insert(i);
+        } else if c == '('
+            || c == ')'
+            || c == ','
+            || c == '*'
+            || c == '/'
+            || c == '^'
+            || c == '%'
+            || c == '='
+            || c == '?'
+            || c == ':'
+        {
+            end_token(expression, &mut output, &mut current_token, i);
+            let span = i..i + 1;
+            output.push(match c {
+                '(' => Token::LeftParenthesis(span),
+                ')' => Token::RightParenthesis(span),
+                ',' => Token::Comma(span),
+                '?' => Token::Question(span),
+                ':' => Token::Colon(span),
+                _ => Token::Operator(span),
+            });
+        } else if c == '<' || c == '>' || c == '!' || c == '&' || c == '|' {
+            end_token(expression, &mut output, &mut current_token, i);
+            match (c, next) {
+                ('<', Some('='))
+                | ('>', Some('='))
+                | ('!', Some('='))
+                | ('<', Some('>'))
+                | ('&', Some('&'))
+                | ('|', Some('|')) => {
+                    output.push(Token::Operator(i..i + 2));
+                    // the second character is part of this operator
+                    chars.next();
+                    last_char = next.unwrap_or_default();
+                    continue;
                 }
-                else if is_number(next) && (is_operator(last_char) || is_whitespace(last_char) || last_char == '('){
-                    current_token.push(c);
+                ('&', _) | ('|', _) => return Err(syntax_error(expression, c, i)),
+                _ => output.push(Token::Operator(i..i + 1)),
+            }
+        } else if c == '-' || c == '+' {
+            // could be indicating negative/positive number or operator
+            // if it is the first valid token in an expression/subexpression, its a negative/positive number
+            // if it is the first valid token after an operator, its a negative/positive number
+            // if it is the first valid token after an open parenthesis, its a negative/positive number
+            // if it is the first valid token after a comma between function arguments, its a negative/positive number
+            // if it is the first valid token after a decimal point, its a negative/positive number
+            // if it is the first valid token after an identifier or number, its an operator
+
+            let follows_operand = !matches!(
+                output.last(),
+                Some(
+                    Token::LeftParenthesis(_)
+                        | Token::Comma(_)
+                        | Token::Operator(_)
+                        | Token::Question(_)
+                        | Token::Colon(_)
+                )
+            );
+            if last_char == char::default() {
+                // a sign at the start of the expression belongs to a number that follows it, and is
+                // otherwise a prefix operator, as in -a or -(a + b)
+                match next {
+                    Some(next) if is_number(next) => current_token = Some(i),
+                    _ => output.push(Token::Operator(i..i + 1)),
                 }
-                else {
-                    current_token = output_token!(output, current_token);
-                    output.push(c.to_string());
+            } else if let Some(next) = next {
+                if (is_operator(next) || next == '(') || (is_number(next) && follows_operand) {
+                    end_token(expression, &mut output, &mut current_token, i);
+                    output.push(Token::Operator(i..i + 1));
+                } else if is_number(next)
+                    && (is_operator(last_char)
+                        || is_whitespace(last_char)
+                        || last_char == '('
+                        || last_char == ',')
+                {
+                    current_token = Some(i);
+                } else {
+                    end_token(expression, &mut output, &mut current_token, i);
+                    output.push(Token::Operator(i..i + 1));
                 }
+            } else {
+                // a trailing sign can only be an operator
+                end_token(expression, &mut output, &mut current_token, i);
+                output.push(Token::Operator(i..i + 1));
             }
-         }
-        else{
-            panic!("Unexpected character: {}", c);
-        }        
+        } else {
+            return Err(syntax_error(expression, c, i));
+        }
         last_char = c;
     }
 
-    output_token!(output, current_token);
+    end_token(
+        expression,
+        &mut output,
+        &mut current_token,
+        expression.len(),
+    );
+
+    Ok(output)
+}
 
-    output
+/// Ends the number or identifier being read, if there is one, at the given byte offset.
+fn end_token(
+    expression: &str,
+    output: &mut Vec<Token>,
+    current_token: &mut Option<usize>,
+    end: usize,
+) {
+    if let Some(start) = current_token.take() {
+        let text = &expression[start..end];
+        output.push(if Decimal::from_str_exact(text).is_ok() {
+            Token::Number(start..end)
+        } else if matches!(text, "and" | "or" | "not") {
+            Token::Operator(start..end)
+        } else {
+            Token::Identifier(start..end)
+        });
+    }
+}
 
+/// An unexpected character at the given byte offset. Errors report character offsets.
+fn syntax_error(expression: &str, c: char, i: usize) -> ExpressurError {
+    ExpressurError::SyntaxError {
+        token: c.to_string(),
+        position: expression[..i].chars().count(),
+    }
 }
 
+/// Pushes a token onto a list of tokens unless it is empty, with where it started if given, and
+/// returns an empty string for the next token. The tokenizer no longer builds tokens as strings,
+/// and this is only kept for code that still uses it.
+#[deprecated(note = "use tokenize, which returns typed tokens with their spans")]
 #[macro_export]
 macro_rules! output_token {
     ($output:ident, $current_token:ident) => {{
         if !$current_token.is_empty() {
             $output.push($current_token);
-        }        
+        }
+        String::default()
+    }};
+    ($output:ident, $current_token:ident, $token_start:ident) => {{
+        if !$current_token.is_empty() {
+            $output.push(($current_token, $token_start));
+        }
         String::default()
-    }        
-    };
+    }};
 }
 
-
 fn is_number(c: char) -> bool {
     c.is_ascii_digit() || c == '.'
 }
@@ -87,15 +225,24 @@ fn is_whitespace(c: char) -> bool {
 }
 
 fn is_token_character(c: char) -> bool {
-    c.is_alphanumeric() ||  c == '_' || is_number(c)
+    c.is_alphanumeric() || c == '_' || is_number(c)
 }
 
 fn is_operator(c: char) -> bool {
-    c == '+' || c == '-' || c == '*' || c == '/' || c == '^' || c == '=' || c == '%' 
-}
-
-fn is_operator_str(s: String) -> bool {
-    matches!(s.as_str(), "+" | "-" | "*" | "/" | "^" | "=" | "%")
+    c == '+'
+        || c == '-'
+        || c == '*'
+        || c == '/'
+        || c == '^'
+        || c == '='
+        || c == '%'
+        || c == '<'
+        || c == '>'
+        || c == '!'
+        || c == '&'
+        || c == '|'
+        || c == '?'
+        || c == ':'
 }
 
 #[test]
@@ -110,7 +257,10 @@ fn test_batch_tokenize() {
         ("-1 - -1", vec!["-1", "-", "-1"]),
         ("-1 - +1", vec!["-1", "-", "+1"]),
         ("-1-+1", vec!["-1", "-", "+1"]),
-        ("-14-+12/(-2*-54)", vec!["-14", "-", "+12", "/", "(", "-2", "*", "-54", ")"]),
+        (
+            "-14-+12/(-2*-54)",
+            vec!["-14", "-", "+12", "/", "(", "-2", "*", "-54", ")"],
+        ),
         ("1 + 1", vec!["1", "+", "1"]),
         ("1 + (-1 + 2)", vec!["1", "+", "(", "-1", "+", "2", ")"]),
         ("1 * a", vec!["1", "*", "a"]),
@@ -120,12 +270,132 @@ fn test_batch_tokenize() {
         ("1 ^ abn", vec!["1", "^", "abn"]),
         ("1 + abn.b", vec!["1", "+", "abn.b"]),
         ("(1 + 1)*2", vec!["(", "1", "+", "1", ")", "*", "2"]),
-        ("(1 + cash.cycle)*2", vec!["(", "1", "+", "cash.cycle", ")", "*", "2"]),
+        (
+            "(1 + cash.cycle)*2",
+            vec!["(", "1", "+", "cash.cycle", ")", "*", "2"],
+        ),
         ("2 / 1", vec!["2", "/", "1"]),
         ("1 +555", vec!["1", "+", "555"]),
         ("1+ 555", vec!["1", "+", "555"]),
+        ("max(a, -1)", vec!["max", "(", "a", ",", "-1", ")"]),
+        (
+            "round(a,-1.5,2)",
+            vec!["round", "(", "a", ",", "-1.5", ",", "2", ")"],
+        ),
+        ("a<=b", vec!["a", "<=", "b"]),
+        ("a >= -1", vec!["a", ">=", "-1"]),
+        ("a<-1", vec!["a", "<", "-1"]),
+        ("a != b", vec!["a", "!=", "b"]),
+        ("a<>b", vec!["a", "<>", "b"]),
+        (
+            "(a>b)=(a<b)",
+            vec!["(", "a", ">", "b", ")", "=", "(", "a", "<", "b", ")"],
+        ),
+        ("a&&!b||c", vec!["a", "&&", "!", "b", "||", "c"]),
+        (
+            "a and -1 or not b",
+            vec!["a", "and", "-1", "or", "not", "b"],
+        ),
+        ("!-1", vec!["!", "-1"]),
+        ("a?-1:b", vec!["a", "?", "-1", ":", "b"]),
+        ("-a", vec!["-", "a"]),
+        ("-(a + b)", vec!["-", "(", "a", "+", "b", ")"]),
+        ("2 * -x", vec!["2", "*", "-", "x"]),
+        ("+a--b", vec!["+", "a", "-", "-", "b"]),
     ];
     for test in tests {
-        assert_eq!(tokenize(test.0), test.1, "Failed to tokenize: {}", test.0);
+        let tokens: Vec<&str> = tokenize(test.0)
+            .unwrap()
+            .iter()
+            .map(|token| token.text(test.0))
+            .collect();
+        assert_eq!(tokens, test.1, "Failed to tokenize: {}", test.0);
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_tokenize_spans() {
+    let tokens = tokenize("(1 + abn)*-2").unwrap();
+    let expected = vec![
+        Token::LeftParenthesis(0..1),
+        Token::Number(1..2),
+        Token::Operator(3..4),
+        Token::Identifier(5..8),
+        Token::RightParenthesis(8..9),
+        Token::Operator(9..10),
+        Token::Number(10..12),
+    ];
+    assert_eq!(tokens, expected);
+
+    let tokens = tokenize("not a ? 1 : b <= 2, 1x").unwrap();
+    let expected = vec![
+        Token::Operator(0..3),
+        Token::Identifier(4..5),
+        Token::Question(6..7),
+        Token::Number(8..9),
+        Token::Colon(10..11),
+        Token::Identifier(12..13),
+        Token::Operator(14..16),
+        Token::Number(17..18),
+        Token::Comma(18..19),
+        Token::Identifier(20..22),
+    ];
+    assert_eq!(tokens, expected);
+
+    // spans are byte offsets, so they stay valid slices of non-ASCII expressions
+    let expression = "größe * 2";
+    let tokens = tokenize(expression).unwrap();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Identifier(0..7),
+            Token::Operator(8..9),
+            Token::Number(10..11)
+        ]
+    );
+    assert_eq!(tokens[0].text(expression), "größe");
+}
+
+#[test]
+fn test_tokenize_unexpected_character() {
+    assert_eq!(
+        tokenize("1 # 2"),
+        Err(ExpressurError::SyntaxError {
+            token: "#".to_string(),
+            position: 2
+        })
+    );
+    assert_eq!(
+        tokenize("1 & 2"),
+        Err(ExpressurError::SyntaxError {
+            token: "&".to_string(),
+            position: 2
+        })
+    );
+}
+#[test]
+fn test_tokenize_unexpected_character_positions_are_characters() {
+    assert_eq!(
+        tokenize("größe # 2"),
+        Err(ExpressurError::SyntaxError {
+            token: "#".to_string(),
+            position: 6
+        })
+    );
+}
+
+#[test]
+#[allow(deprecated)]
+fn test_output_token() {
+    let mut output: Vec<String> = Vec::new();
+    let current_token = "ab".to_string();
+    let current_token = crate::output_token!(output, current_token);
+    let current_token = crate::output_token!(output, current_token);
+    assert_eq!(output, vec!["ab"]);
+    assert!(current_token.is_empty());
+
+    let mut output: Vec<(String, usize)> = Vec::new();
+    let (current_token, token_start) = ("c".to_string(), 4);
+    crate::output_token!(output, current_token, token_start);
+    assert_eq!(output, vec![("c".to_string(), 4)]);
+}